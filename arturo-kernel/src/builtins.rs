@@ -0,0 +1,55 @@
+//! Arturo builtin index, shared with the language server.
+//!
+//! The data is `language-server/seed-cache.json` — the same offline seed the
+//! LSP's signature indexer starts from — compiled into the kernel binary.
+
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::OnceLock};
+
+const SEED_CACHE: &str = include_str!("../../language-server/seed-cache.json");
+
+#[derive(Debug, Deserialize)]
+pub struct Builtin {
+    /// e.g. `"print value :any -> :nothing"`
+    pub signature: String,
+    pub description: String,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    #[serde(default, rename = "attrs")]
+    pub attributes: Vec<Attribute>,
+    pub returns: String,
+    pub module: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Param {
+    pub name: String,
+    /// One or more `/`-separated types, e.g. `":string/:block"`.
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Attribute {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub description: String,
+}
+
+#[derive(Deserialize)]
+struct SeedCache {
+    signatures: BTreeMap<String, Builtin>,
+}
+
+/// All builtins, keyed (and therefore sorted) by name.
+pub fn all() -> &'static BTreeMap<String, Builtin> {
+    static BUILTINS: OnceLock<BTreeMap<String, Builtin>> = OnceLock::new();
+    BUILTINS.get_or_init(|| match serde_json::from_str::<SeedCache>(SEED_CACHE) {
+        Ok(cache) => cache.signatures,
+        Err(e) => {
+            eprintln!("[arturo-kernel] Could not parse builtin index: {e}");
+            BTreeMap::new()
+        }
+    })
+}
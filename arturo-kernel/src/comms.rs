@@ -0,0 +1,172 @@
+//! Comms: custom message channels between Arturo code and the front-end,
+//! as used by interactive widgets.
+//!
+//! Arturo drives its side through `#%arturo-comm` lines on stdout (see
+//! [`crate::markers::COMM`]): it registers a handler function for a target
+//! name, opens comms, and sends or closes them. Messages from the front-end
+//! are dispatched by the comm's target name to that handler, which the
+//! kernel calls in the session as `handler event commId data`.
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// One `#%arturo-comm` line.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Action {
+    /// Call `handler` for comms with this target, whoever opens them.
+    Register { target_name: String, handler: String },
+    Open {
+        comm_id: String,
+        target_name: String,
+        #[serde(default)]
+        data: Map<String, Value>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+    },
+    Msg {
+        comm_id: String,
+        #[serde(default)]
+        data: Map<String, Value>,
+    },
+    Close {
+        comm_id: String,
+        #[serde(default)]
+        data: Map<String, Value>,
+    },
+}
+
+pub fn parse(payload: &str) -> Result<Action, String> {
+    serde_json::from_str(payload).map_err(|e| e.to_string())
+}
+
+/// The session's registered targets and open comms.
+#[derive(Debug, Default)]
+pub struct Comms {
+    /// Handler function by target name.
+    targets: BTreeMap<String, String>,
+    /// Target name by comm id.
+    open: BTreeMap<String, String>,
+}
+
+impl Comms {
+    pub fn register(&mut self, target_name: &str, handler: &str) {
+        self.targets.insert(target_name.to_string(), handler.to_string());
+    }
+
+    /// The handler registered for `target_name`.
+    pub fn handler(&self, target_name: &str) -> Option<&str> {
+        self.targets.get(target_name).map(String::as_str)
+    }
+
+    /// The handler for messages on an open comm.
+    pub fn handler_for(&self, comm_id: &str) -> Option<&str> {
+        self.open.get(comm_id).and_then(|target| self.handler(target))
+    }
+
+    pub fn opened(&mut self, comm_id: &str, target_name: &str) {
+        self.open.insert(comm_id.to_string(), target_name.to_string());
+    }
+
+    pub fn closed(&mut self, comm_id: &str) {
+        self.open.remove(comm_id);
+    }
+
+    /// Update the registry for a `comm_open`, `comm_msg` or `comm_close` from
+    /// the front-end, and return the handler call to run for it: `None` when
+    /// no handler is registered for the comm's target.
+    pub fn dispatch(&mut self, msg_type: &str, content: &Value) -> Option<String> {
+        let comm_id = content["comm_id"].as_str()?;
+        let handler = match msg_type {
+            "comm_open" => {
+                let target_name = content["target_name"].as_str()?;
+                let handler = self.handler(target_name)?.to_string();
+                self.opened(comm_id, target_name);
+                Some(handler)
+            }
+            _ => self.handler_for(comm_id).map(str::to_string),
+        };
+        if msg_type == "comm_close" {
+            self.closed(comm_id);
+        }
+        let event = msg_type.strip_prefix("comm_").unwrap_or(msg_type);
+        handler.map(|handler| call(&handler, event, comm_id, &content["data"]))
+    }
+
+    /// The `comms` of a `comm_info_reply`, optionally for one target only.
+    pub fn info(&self, target_name: Option<&str>) -> Value {
+        let comms: Map<String, Value> = self
+            .open
+            .iter()
+            .filter(|(_, target)| target_name.is_none_or(|name| name == target.as_str()))
+            .map(|(id, target)| (id.clone(), json!({ "target_name": target })))
+            .collect();
+        Value::Object(comms)
+    }
+}
+
+/// The Arturo call that hands a front-end comm event to `handler`; `data`
+/// is passed as a JSON string.
+fn call(handler: &str, event: &str, comm_id: &str, data: &Value) -> String {
+    format!("{handler} {} {} {}", quote(event), quote(comm_id), quote(&data.to_string()))
+}
+
+fn quote(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Action, Comms};
+    use serde_json::json;
+
+    #[test]
+    fn actions() {
+        assert_eq!(
+            parse(r#"{"action": "register", "target_name": "slider", "handler": "onSlider"}"#),
+            Ok(Action::Register { target_name: "slider".to_string(), handler: "onSlider".to_string() })
+        );
+        let Ok(Action::Msg { comm_id, data }) = parse(r#"{"action": "msg", "comm_id": "c1", "data": {"value": 3}}"#)
+        else {
+            panic!("not a msg");
+        };
+        assert_eq!(comm_id, "c1");
+        assert_eq!(data["value"], 3);
+        assert!(parse(r#"{"action": "open", "comm_id": "c1"}"#).is_err());
+        assert!(parse(r#"{"action": "shout"}"#).is_err());
+    }
+
+    #[test]
+    fn dispatch_by_target() {
+        let mut comms = Comms::default();
+        comms.register("slider", "onSlider");
+        comms.opened("c1", "slider");
+        comms.opened("c2", "chart");
+        assert_eq!(comms.handler_for("c1"), Some("onSlider"));
+        assert_eq!(comms.handler_for("c2"), None);
+        assert_eq!(comms.info(Some("slider")), json!({ "c1": { "target_name": "slider" } }));
+
+        comms.closed("c1");
+        assert_eq!(comms.handler_for("c1"), None);
+        assert_eq!(comms.info(None), json!({ "c2": { "target_name": "chart" } }));
+    }
+
+    #[test]
+    fn front_end_messages_call_the_handler() {
+        let mut comms = Comms::default();
+        comms.register("slider", "onSlider");
+        assert_eq!(comms.dispatch("comm_open", &json!({ "comm_id": "c9", "target_name": "chart" })), None);
+
+        let open = json!({ "comm_id": "c1", "target_name": "slider", "data": {} });
+        assert_eq!(comms.dispatch("comm_open", &open).as_deref(), Some(r#"onSlider "open" "c1" "{}""#));
+        let msg = json!({ "comm_id": "c1", "data": { "label": "say \"hi\"" } });
+        assert_eq!(
+            comms.dispatch("comm_msg", &msg).as_deref(),
+            Some(r#"onSlider "msg" "c1" "{\"label\":\"say \\\"hi\\\"\"}""#)
+        );
+        assert!(comms.dispatch("comm_close", &json!({ "comm_id": "c1" })).is_some());
+        assert_eq!(comms.info(None), json!({}));
+    }
+}
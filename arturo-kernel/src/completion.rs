@@ -0,0 +1,149 @@
+//! `complete_request` support: builtins plus names defined in the session.
+
+use crate::{builtins, lexer, preamble::Preamble};
+use serde_json::{json, Value};
+
+/// Build the content of a `complete_reply`.
+///
+/// `cursor_pos` and the returned `cursor_start` / `cursor_end` count Unicode
+/// code points, as the Jupyter protocol requires.
+pub fn complete(code: &str, cursor_pos: usize, preamble: &Preamble) -> Value {
+    let cursor = byte_offset(code, cursor_pos);
+    let (start, end) = word_bounds(code, cursor);
+    let prefix = &code[start..cursor];
+
+    let mut matches: Vec<(String, &str)> = Vec::new();
+    if !prefix.is_empty() && !in_string_or_comment(code, cursor) {
+        let mut session: Vec<&str> = preamble.names().filter(|n| n.starts_with(prefix)).collect();
+        session.sort_unstable();
+        session.dedup();
+        for name in session {
+            matches.push((name.to_string(), "variable"));
+        }
+        for name in builtins::all().keys().filter(|n| n.starts_with(prefix)) {
+            if !matches.iter().any(|(m, _)| m == name) {
+                matches.push((name.clone(), "function"));
+            }
+        }
+    }
+
+    let cursor_start = code[..start].chars().count();
+    let cursor_end = code[..end].chars().count();
+    let types: Vec<Value> = matches
+        .iter()
+        .map(|(name, kind)| {
+            let mut entry = json!({
+                "start": cursor_start,
+                "end": cursor_end,
+                "text": name,
+                "type": kind,
+            });
+            if let Some(builtin) = builtins::all().get(name).filter(|_| *kind == "function") {
+                entry["signature"] = json!(builtin.signature);
+            }
+            entry
+        })
+        .collect();
+
+    json!({
+        "status": "ok",
+        "matches": matches.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+        "cursor_start": cursor_start,
+        "cursor_end": cursor_end,
+        "metadata": { "_jupyter_types_experimental": types }
+    })
+}
+
+/// Byte offset of the `chars`-th code point, clamped to the end of `code`.
+pub fn byte_offset(code: &str, chars: usize) -> usize {
+    code.char_indices().nth(chars).map_or(code.len(), |(i, _)| i)
+}
+
+/// Byte range of the identifier around `cursor`, covering hyphenated names
+/// (`to-upper`) and a trailing `?` (`even?`).
+pub fn word_bounds(code: &str, cursor: usize) -> (usize, usize) {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '?';
+
+    let mut start = cursor;
+    for (i, c) in code[..cursor].char_indices().rev() {
+        if !is_ident(c) {
+            break;
+        }
+        start = i;
+    }
+    // A name never starts with `-` or `?` (`x -y`, `a ?b`).
+    while let Some(c) = code[start..cursor].chars().next().filter(|c| *c == '-' || *c == '?') {
+        start += c.len_utf8();
+    }
+
+    let mut end = cursor;
+    for (i, c) in code[cursor..].char_indices() {
+        if !is_ident(c) {
+            break;
+        }
+        end = cursor + i + c.len_utf8();
+        if c == '?' {
+            break;
+        }
+    }
+
+    (start, end)
+}
+
+fn in_string_or_comment(code: &str, cursor: usize) -> bool {
+    match lexer::tokenize(&code[..cursor]) {
+        Ok(tokens) => tokens.last().is_some_and(|t| {
+            t.end == cursor && matches!(t.kind, lexer::TokenKind::String | lexer::TokenKind::Comment)
+        }),
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete, word_bounds};
+    use crate::{preamble::Preamble, testing::session};
+
+    fn matches(code: &str) -> Vec<String> {
+        let reply = complete(code, code.chars().count(), &session("printAll: 1\nprints: printAll + 1\nprintAll: 3"));
+        serde_json::from_value(reply["matches"].clone()).unwrap()
+    }
+
+    #[test]
+    fn bounds_cover_hyphenated_and_predicate_names() {
+        let code = "print to-upper name";
+        assert_eq!(word_bounds(code, 9), (6, 14));
+        assert_eq!(word_bounds("even? 3", 2), (0, 5));
+        assert_eq!(word_bounds("ready?x", 3), (0, 6));
+        assert_eq!(word_bounds("x -y", 4), (3, 4));
+        assert_eq!(word_bounds("a ?b", 4), (3, 4));
+        assert_eq!(word_bounds("[x]", 1), (1, 2));
+    }
+
+    #[test]
+    fn cursor_counts_code_points() {
+        let code = "s: «héllo» to";
+        let reply = complete(code, code.chars().count(), &Preamble::default());
+        assert_eq!(reply["cursor_start"], 11);
+        assert_eq!(reply["cursor_end"], 13);
+        assert_eq!(reply["matches"][0], "to");
+    }
+
+    #[test]
+    fn nothing_inside_strings_or_comments() {
+        assert!(matches("print \"pri").is_empty());
+        assert!(matches("x: 1 ; pri").is_empty());
+        assert!(matches("s: {pri").is_empty());
+        assert!(matches("").is_empty());
+    }
+
+    #[test]
+    fn session_names_come_first_without_duplicates() {
+        assert_eq!(matches("pri"), ["printAll", "prints", "print"]);
+        let reply = complete("pri", 3, &session("prints: 2"));
+        let types = &reply["metadata"]["_jupyter_types_experimental"];
+        assert_eq!(types[0]["type"], "variable");
+        assert_eq!(types[1]["type"], "function");
+        assert!(types[1]["signature"].as_str().is_some_and(|s| s.starts_with("print")));
+    }
+}
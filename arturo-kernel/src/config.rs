@@ -0,0 +1,368 @@
+//! How the kernel starts Arturo: executable, extra arguments, working
+//! directory, environment and per-cell limits; whether it rejects replayed
+//! messages; and whether history keeps cell output.
+//!
+//! Settings come from three places, later ones overriding earlier ones field
+//! by field (`env` entries are merged):
+//!
+//! 1. a JSON config file — `--config FILE`, `ARTURO_KERNEL_CONFIG`, or
+//!    `arturo-kernel.json` in the Jupyter config directory;
+//! 2. `ARTURO_KERNEL_EXECUTABLE`, `ARTURO_KERNEL_ARGS`, `ARTURO_KERNEL_CWD`,
+//!    `ARTURO_KERNEL_TIMEOUT`, `ARTURO_KERNEL_MEMORY_LIMIT`,
+//!    `ARTURO_KERNEL_OUTPUT_LIMIT`, `ARTURO_KERNEL_SIGTERM_DELAY`,
+//!    `ARTURO_KERNEL_SIGKILL_DELAY`, `ARTURO_KERNEL_REJECT_DUPLICATE_IDS` and
+//!    `ARTURO_KERNEL_HISTORY_OUTPUT`;
+//! 3. flags in the kernelspec `argv`, before the connection file.
+
+use crate::limits::Limits;
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fs, path::PathBuf, process::Command, str::FromStr, time::Duration};
+
+pub const USAGE: &str = "Usage: arturo-kernel [--config FILE] [--arturo PATH] [--arturo-arg ARG]... \
+                         [--cwd DIR] [--env NAME=VALUE]... [--timeout SECS] [--memory-limit MB] \
+                         [--output-limit KB] [--sigterm-delay SECS] [--sigkill-delay SECS] \
+                         [--reject-duplicate-ids] [--history-output] <connection-file>";
+
+/// Default delays of interrupt escalation, in seconds.
+const SIGTERM_DELAY: f64 = 2.0;
+const SIGKILL_DELAY: f64 = 3.0;
+
+/// Longest accepted timeout or delay, in seconds (a year).
+const MAX_SECS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The Arturo interpreter, or a wrapper that runs it.
+    pub executable: String,
+    /// Passed before the kernel's own arguments (`--no-color …`).
+    pub args: Vec<String>,
+    /// Working directory for Arturo processes and `%load`.
+    pub cwd: Option<PathBuf>,
+    /// Added to the kernel's environment for Arturo processes.
+    pub env: BTreeMap<String, String>,
+    /// Wall-clock limit for one cell.
+    pub timeout_secs: Option<f64>,
+    /// Address-space limit for an Arturo process (Unix only).
+    pub memory_limit_mb: Option<u64>,
+    /// Limit on one cell's stdout plus stderr.
+    pub output_limit_kb: Option<u64>,
+    /// After an interrupt, how long to wait before sending SIGTERM.
+    pub sigterm_delay_secs: Option<f64>,
+    /// After SIGTERM, how long to wait before sending SIGKILL.
+    pub sigkill_delay_secs: Option<f64>,
+    /// Drop shell and control messages whose `msg_id` was already seen.
+    pub reject_duplicate_ids: bool,
+    /// Save each cell's stdout in the history file, not only its input.
+    pub history_output: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            executable: "arturo".to_string(),
+            args: Vec::new(),
+            cwd: None,
+            env: BTreeMap::new(),
+            timeout_secs: None,
+            memory_limit_mb: None,
+            output_limit_kb: None,
+            sigterm_delay_secs: None,
+            sigkill_delay_secs: None,
+            reject_duplicate_ids: false,
+            history_output: false,
+        }
+    }
+}
+
+/// Settings given as command-line flags.
+#[derive(Debug, Default)]
+struct Flags {
+    config: Option<PathBuf>,
+    executable: Option<String>,
+    args: Option<Vec<String>>,
+    cwd: Option<PathBuf>,
+    env: BTreeMap<String, String>,
+    timeout_secs: Option<f64>,
+    memory_limit_mb: Option<u64>,
+    output_limit_kb: Option<u64>,
+    sigterm_delay_secs: Option<f64>,
+    sigkill_delay_secs: Option<f64>,
+    reject_duplicate_ids: bool,
+    history_output: bool,
+    connection_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Parse the kernel's command line (without the program name) and load
+    /// the configuration. Returns the connection file and the configuration.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<(PathBuf, Config), String> {
+        let flags = parse_flags(args)?;
+        let connection_file = flags.connection_file.clone().ok_or_else(|| USAGE.to_string())?;
+
+        let mut config = match flags.config.clone().or_else(|| env::var_os("ARTURO_KERNEL_CONFIG").map(PathBuf::from)) {
+            Some(path) => Config::read(&path)?,
+            None => match jupyter_config_dir().map(|dir| dir.join("arturo-kernel.json")) {
+                Some(path) if path.exists() => Config::read(&path)?,
+                _ => Config::default(),
+            },
+        };
+        config.apply_env_vars()?;
+        config.apply(flags);
+        config.check()?;
+        Ok((connection_file, config))
+    }
+
+    /// Reject durations that cannot be turned into a deadline.
+    fn check(&self) -> Result<(), String> {
+        let durations = [
+            ("timeout", self.timeout_secs),
+            ("sigterm delay", self.sigterm_delay_secs),
+            ("sigkill delay", self.sigkill_delay_secs),
+        ];
+        for (name, secs) in durations {
+            if let Some(secs) = secs.filter(|s| !s.is_finite() || *s > MAX_SECS) {
+                return Err(format!("The {name} must be at most {MAX_SECS} seconds, got {secs}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn read(path: &PathBuf) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        serde_json::from_str(&text).map_err(|e| format!("Invalid config file {}: {e}", path.display()))
+    }
+
+    fn apply_env_vars(&mut self) -> Result<(), String> {
+        if let Ok(executable) = env::var("ARTURO_KERNEL_EXECUTABLE") {
+            self.executable = executable;
+        }
+        if let Ok(args) = env::var("ARTURO_KERNEL_ARGS") {
+            self.args = args.split_whitespace().map(str::to_string).collect();
+        }
+        if let Some(cwd) = env::var_os("ARTURO_KERNEL_CWD") {
+            self.cwd = Some(PathBuf::from(cwd));
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_TIMEOUT") {
+            self.timeout_secs = Some(number("ARTURO_KERNEL_TIMEOUT", &value)?);
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_MEMORY_LIMIT") {
+            self.memory_limit_mb = Some(number("ARTURO_KERNEL_MEMORY_LIMIT", &value)?);
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_OUTPUT_LIMIT") {
+            self.output_limit_kb = Some(number("ARTURO_KERNEL_OUTPUT_LIMIT", &value)?);
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_SIGTERM_DELAY") {
+            self.sigterm_delay_secs = Some(number("ARTURO_KERNEL_SIGTERM_DELAY", &value)?);
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_SIGKILL_DELAY") {
+            self.sigkill_delay_secs = Some(number("ARTURO_KERNEL_SIGKILL_DELAY", &value)?);
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_REJECT_DUPLICATE_IDS") {
+            self.reject_duplicate_ids = switch("ARTURO_KERNEL_REJECT_DUPLICATE_IDS", &value)?;
+        }
+        if let Ok(value) = env::var("ARTURO_KERNEL_HISTORY_OUTPUT") {
+            self.history_output = switch("ARTURO_KERNEL_HISTORY_OUTPUT", &value)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, flags: Flags) {
+        if let Some(executable) = flags.executable {
+            self.executable = executable;
+        }
+        if let Some(args) = flags.args {
+            self.args = args;
+        }
+        if let Some(cwd) = flags.cwd {
+            self.cwd = Some(cwd);
+        }
+        self.env.extend(flags.env);
+        self.timeout_secs = flags.timeout_secs.or(self.timeout_secs);
+        self.memory_limit_mb = flags.memory_limit_mb.or(self.memory_limit_mb);
+        self.output_limit_kb = flags.output_limit_kb.or(self.output_limit_kb);
+        self.sigterm_delay_secs = flags.sigterm_delay_secs.or(self.sigterm_delay_secs);
+        self.sigkill_delay_secs = flags.sigkill_delay_secs.or(self.sigkill_delay_secs);
+        self.reject_duplicate_ids |= flags.reject_duplicate_ids;
+        self.history_output |= flags.history_output;
+    }
+
+    /// The per-cell limits; zero means no limit.
+    pub fn limits(&self) -> Limits {
+        let delay = |secs: Option<f64>, default: f64| Duration::from_secs_f64(secs.unwrap_or(default).max(0.0));
+        Limits {
+            timeout: self.timeout_secs.filter(|s| *s > 0.0).map(Duration::from_secs_f64),
+            memory_mb: self.memory_limit_mb.filter(|mb| *mb > 0),
+            output_bytes: self.output_limit_kb.filter(|kb| *kb > 0).map(|kb| kb as usize * 1024),
+            sigterm_delay: delay(self.sigterm_delay_secs, SIGTERM_DELAY),
+            sigkill_delay: delay(self.sigkill_delay_secs, SIGKILL_DELAY),
+        }
+    }
+
+    /// A command for the configured interpreter; callers add their own
+    /// arguments and pipes.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.executable);
+        cmd.args(&self.args).envs(&self.env);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        self.limits().apply(&mut cmd);
+        cmd
+    }
+
+    /// `path` relative to the configured working directory.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => cwd.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Flags, String> {
+    let mut flags = Flags::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if flags.connection_file.replace(PathBuf::from(arg)).is_some() {
+                return Err(USAGE.to_string());
+            }
+            continue;
+        }
+
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| format!("{name} needs a value\n{USAGE}"));
+
+        match name.as_str() {
+            "--config" => flags.config = Some(PathBuf::from(value()?)),
+            "--arturo" => flags.executable = Some(value()?),
+            "--arturo-arg" => flags.args.get_or_insert_with(Vec::new).push(value()?),
+            "--cwd" => flags.cwd = Some(PathBuf::from(value()?)),
+            "--env" => {
+                let assignment = value()?;
+                let (key, val) = assignment
+                    .split_once('=')
+                    .ok_or_else(|| format!("--env expects NAME=VALUE, got `{assignment}`"))?;
+                flags.env.insert(key.to_string(), val.to_string());
+            }
+            "--timeout" => flags.timeout_secs = Some(number(&name, &value()?)?),
+            "--memory-limit" => flags.memory_limit_mb = Some(number(&name, &value()?)?),
+            "--output-limit" => flags.output_limit_kb = Some(number(&name, &value()?)?),
+            "--sigterm-delay" => flags.sigterm_delay_secs = Some(number(&name, &value()?)?),
+            "--sigkill-delay" => flags.sigkill_delay_secs = Some(number(&name, &value()?)?),
+            "--reject-duplicate-ids" => flags.reject_duplicate_ids = true,
+            "--history-output" => flags.history_output = true,
+            _ => return Err(format!("Unknown option {name}\n{USAGE}")),
+        }
+    }
+    Ok(flags)
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("{name} expects a number, got `{value}`"))
+}
+
+fn switch(name: &str, value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "" | "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("{name} expects true or false, got `{value}`")),
+    }
+}
+
+/// Jupyter's per-user config directory, following `jupyter --config-dir`.
+fn jupyter_config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("JUPYTER_CONFIG_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    let home = env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?;
+    Some(PathBuf::from(home).join(".jupyter"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_flags, Config};
+    use std::path::PathBuf;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn connection_file_only() {
+        let flags = parse_flags(args(&["/tmp/kernel-1.json"])).unwrap();
+        assert_eq!(flags.connection_file, Some(PathBuf::from("/tmp/kernel-1.json")));
+        assert_eq!(flags.executable, None);
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let mut config: Config =
+            serde_json::from_str(r#"{"executable": "/opt/arturo", "args": ["--debug"], "env": {"A": "1"}}"#).unwrap();
+        let flags = parse_flags(args(&[
+            "--arturo=nix",
+            "--arturo-arg", "run",
+            "--arturo-arg", "--",
+            "--env", "B=2",
+            "--cwd", "/work",
+            "conn.json",
+        ]))
+        .unwrap();
+        config.apply(flags);
+
+        assert_eq!(config.executable, "nix");
+        assert_eq!(config.args, ["run", "--"]);
+        assert_eq!(config.cwd, Some(PathBuf::from("/work")));
+        assert_eq!(config.env.len(), 2);
+    }
+
+    #[test]
+    fn limits() {
+        let mut config = Config::default();
+        config.apply(parse_flags(args(&["--timeout", "2.5", "--output-limit=64", "conn.json"])).unwrap());
+        let limits = config.limits();
+        assert_eq!(limits.timeout, Some(std::time::Duration::from_millis(2500)));
+        assert_eq!(limits.output_bytes, Some(64 * 1024));
+        assert_eq!(limits.memory_mb, None);
+
+        config.apply(parse_flags(args(&["--timeout", "0", "conn.json"])).unwrap());
+        assert_eq!(config.limits().timeout, None);
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let mut config = Config::default();
+        assert!(!config.reject_duplicate_ids);
+        config.apply(parse_flags(args(&["--reject-duplicate-ids", "conn.json"])).unwrap());
+        assert!(config.reject_duplicate_ids);
+        assert_eq!(super::switch("X", "Yes"), Ok(true));
+        assert!(super::switch("X", "maybe").is_err());
+    }
+
+    #[test]
+    fn bad_flags_are_errors() {
+        assert!(parse_flags(args(&["--arturo"])).is_err());
+        assert!(parse_flags(args(&["--env", "NOVALUE", "conn.json"])).is_err());
+        assert!(parse_flags(args(&["--frobnicate", "conn.json"])).is_err());
+        assert!(parse_flags(args(&["a.json", "b.json"])).is_err());
+        assert!(parse_flags(args(&["--timeout", "soon", "conn.json"])).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"exe": "arturo"}"#).is_err());
+    }
+
+    #[test]
+    fn durations_must_fit_a_deadline() {
+        for timeout in ["inf", "NaN", "1e300"] {
+            let mut config = Config::default();
+            config.apply(parse_flags(args(&["--timeout", timeout, "conn.json"])).unwrap());
+            assert!(config.check().is_err(), "{timeout}");
+        }
+        let mut config = Config::default();
+        config.apply(parse_flags(args(&["--timeout", "86400", "--sigkill-delay", "-1", "conn.json"])).unwrap());
+        assert_eq!(config.check(), Ok(()));
+    }
+}
@@ -0,0 +1,226 @@
+//! The connection file: where the kernel's sockets listen, and the key that
+//! signs messages.
+//!
+//! `tcp` endpoints are `tcp://ip:port`; for `ipc`, `ip` is a path prefix and
+//! each socket lives at `<ip>-<port>`, as in jupyter_client. A port of 0 asks
+//! the kernel to pick one; the ports it picked are written back to the file.
+//! A `signature_scheme` other than `hmac-sha256` is refused.
+
+use crate::signing;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use zmq::{Context, Socket, SocketType};
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectionInfo {
+    ip: String,
+    transport: String,
+    shell_port: u16,
+    iopub_port: u16,
+    stdin_port: u16,
+    control_port: u16,
+    hb_port: u16,
+    pub key: String,
+    signature_scheme: String,
+    #[allow(dead_code)]
+    kernel_name: Option<String>,
+}
+
+/// The five sockets of a kernel, in the order [`ConnectionInfo::bind_all`]
+/// returns them.
+#[derive(Debug, Clone, Copy)]
+enum Channel {
+    Shell,
+    IOPub,
+    Stdin,
+    Control,
+    Heartbeat,
+}
+
+const CHANNELS: [Channel; 5] = [Channel::Shell, Channel::IOPub, Channel::Stdin, Channel::Control, Channel::Heartbeat];
+
+impl Channel {
+    fn socket_type(self) -> SocketType {
+        match self {
+            Channel::IOPub => SocketType::PUB,
+            Channel::Heartbeat => SocketType::REP,
+            Channel::Shell | Channel::Stdin | Channel::Control => SocketType::ROUTER,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Shell => "shell",
+            Channel::IOPub => "iopub",
+            Channel::Stdin => "stdin",
+            Channel::Control => "control",
+            Channel::Heartbeat => "heartbeat",
+        }
+    }
+
+    /// The channel's key in the connection file.
+    fn key(self) -> &'static str {
+        match self {
+            Channel::Shell => "shell_port",
+            Channel::IOPub => "iopub_port",
+            Channel::Stdin => "stdin_port",
+            Channel::Control => "control_port",
+            Channel::Heartbeat => "hb_port",
+        }
+    }
+}
+
+impl ConnectionInfo {
+    pub fn load(path: &Path) -> Result<ConnectionInfo, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read connection file {}: {e}", path.display()))?;
+        let info: ConnectionInfo = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid connection file {}: {e}", path.display()))?;
+        // Without a key nothing is signed, whatever the scheme.
+        if !info.key.is_empty() {
+            signing::check_scheme(&info.signature_scheme)?;
+        }
+        Ok(info)
+    }
+
+    /// Bind the shell, iopub, stdin, control and heartbeat sockets, in that
+    /// order. If any port was 0, the ports chosen are written back to `path`.
+    pub fn bind_all(&mut self, ctx: &Context, path: &Path) -> Result<[Socket; 5], String> {
+        let requested = CHANNELS.map(|channel| *self.port(channel));
+
+        let [shell, iopub, stdin, control, heartbeat] = CHANNELS.map(|channel| self.open(ctx, channel));
+        let sockets = [shell?, iopub?, stdin?, control?, heartbeat?];
+
+        if CHANNELS.map(|channel| *self.port(channel)) != requested {
+            self.write_ports(path)?;
+        }
+        Ok(sockets)
+    }
+
+    fn open(&mut self, ctx: &Context, channel: Channel) -> Result<Socket, String> {
+        let socket = ctx
+            .socket(channel.socket_type())
+            .map_err(|e| format!("Could not create the {} socket: {e}", channel.name()))?;
+        let port = self.bind(&socket, channel)?;
+        *self.port(channel) = port;
+        Ok(socket)
+    }
+
+    fn port(&mut self, channel: Channel) -> &mut u16 {
+        match channel {
+            Channel::Shell => &mut self.shell_port,
+            Channel::IOPub => &mut self.iopub_port,
+            Channel::Stdin => &mut self.stdin_port,
+            Channel::Control => &mut self.control_port,
+            Channel::Heartbeat => &mut self.hb_port,
+        }
+    }
+
+    fn endpoint(&self, port: u16) -> String {
+        match self.transport.as_str() {
+            "ipc" => format!("ipc://{}-{port}", self.ip),
+            transport => format!("{transport}://{}:{port}", self.ip),
+        }
+    }
+
+    /// Bind `socket` to the channel's port, or to a free one if it is 0, and
+    /// return the port it is bound to.
+    fn bind(&mut self, socket: &Socket, channel: Channel) -> Result<u16, String> {
+        let requested = *self.port(channel);
+        let (endpoint, port) = match (self.transport.as_str(), requested) {
+            ("ipc", 0) => {
+                let port = free_ipc_port(&self.ip);
+                (self.endpoint(port), Some(port))
+            }
+            (transport, 0) => (format!("{transport}://{}:*", self.ip), None),
+            _ => (self.endpoint(requested), Some(requested)),
+        };
+
+        socket.bind(&endpoint).map_err(|e| {
+            format!("Could not bind the {} socket to {endpoint}: {e}", channel.name())
+        })?;
+        if let Some(port) = port {
+            return Ok(port);
+        }
+
+        let bound = socket.get_last_endpoint().ok().and_then(Result::ok).unwrap_or_default();
+        bound_port(&bound).ok_or_else(|| format!("Could not tell which port the {} socket is bound to", channel.name()))
+    }
+
+    /// Update the port numbers in the connection file, keeping its other
+    /// fields as they are.
+    fn write_ports(&mut self, path: &Path) -> Result<(), String> {
+        let error = |e: &dyn std::fmt::Display| format!("Could not update connection file {}: {e}", path.display());
+
+        let text = fs::read_to_string(path).map_err(|e| error(&e))?;
+        let mut file: Value = serde_json::from_str(&text).map_err(|e| error(&e))?;
+        for channel in CHANNELS {
+            file[channel.key()] = (*self.port(channel)).into();
+        }
+        let text = serde_json::to_string_pretty(&file).map_err(|e| error(&e))?;
+        fs::write(path, text).map_err(|e| error(&e))
+    }
+}
+
+/// The port of a bound endpoint such as `tcp://127.0.0.1:52117`.
+fn bound_port(endpoint: &str) -> Option<u16> {
+    endpoint.rsplit_once(':')?.1.parse().ok().filter(|&port| port != 0)
+}
+
+/// The lowest port from 1 up whose `<ip>-<port>` socket path is unused.
+fn free_ipc_port(ip: &str) -> u16 {
+    (1..=u16::MAX).find(|port| !ipc_path(ip, *port).exists()).unwrap_or(u16::MAX)
+}
+
+fn ipc_path(ip: &str, port: u16) -> PathBuf {
+    PathBuf::from(format!("{ip}-{port}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bound_port, free_ipc_port, ConnectionInfo};
+    use crate::testing::TempDir;
+    use std::fs;
+
+    fn info(transport: &str, ip: &str) -> ConnectionInfo {
+        serde_json::from_value(serde_json::json!({
+            "ip": ip,
+            "transport": transport,
+            "shell_port": 0,
+            "iopub_port": 0,
+            "stdin_port": 0,
+            "control_port": 0,
+            "hb_port": 0,
+            "key": "",
+            "signature_scheme": "hmac-sha256",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(info("tcp", "127.0.0.1").endpoint(5555), "tcp://127.0.0.1:5555");
+        assert_eq!(info("ipc", "/tmp/kernel-1").endpoint(3), "ipc:///tmp/kernel-1-3");
+    }
+
+    #[test]
+    fn ports_of_bound_endpoints() {
+        assert_eq!(bound_port("tcp://127.0.0.1:52117"), Some(52117));
+        assert_eq!(bound_port("tcp://[::1]:40000"), Some(40000));
+        assert_eq!(bound_port("tcp://127.0.0.1:0"), None);
+        assert_eq!(bound_port(""), None);
+    }
+
+    #[test]
+    fn ipc_ports_skip_existing_paths() {
+        let dir = TempDir::new();
+        let ip = dir.path().join("kernel").display().to_string();
+        fs::write(format!("{ip}-1"), "").unwrap();
+        fs::write(format!("{ip}-2"), "").unwrap();
+        assert_eq!(free_ipc_port(&ip), 3);
+    }
+}
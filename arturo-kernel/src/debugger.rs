@@ -0,0 +1,145 @@
+//! `debug_request` support: the Debug Adapter Protocol, carried over the
+//! control channel.
+//!
+//! Only the handshake a front-end goes through before it can debug is
+//! implemented: `initialize`, `setBreakpoints` and `configurationDone`.
+//! Breakpoints are remembered but not verified, since Arturo cannot be
+//! stepped yet; every other command fails with a DAP error response. The
+//! kernelspec keeps `"debugger": false` until stepping works.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    /// Sequence number of the last message the kernel sent.
+    seq: u64,
+    /// Requested breakpoint lines, by source path.
+    breakpoints: BTreeMap<String, Vec<u64>>,
+}
+
+impl Debugger {
+    /// Answer one DAP request. Returns the response, for the `debug_reply`,
+    /// and any events to publish as `debug_event`.
+    pub fn handle(&mut self, request: &Value) -> (Value, Vec<Value>) {
+        let command = request["command"].as_str().unwrap_or("");
+        let arguments = &request["arguments"];
+
+        match command {
+            "initialize" => {
+                let response = self.response(request, true, capabilities());
+                let initialized = self.event("initialized", json!({}));
+                (response, vec![initialized])
+            }
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or("").to_string();
+                let lines: Vec<u64> = arguments["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|b| b["line"].as_u64())
+                    .collect();
+                let breakpoints: Vec<Value> = lines
+                    .iter()
+                    .map(|line| {
+                        json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "Arturo cannot be stepped yet",
+                        })
+                    })
+                    .collect();
+                self.breakpoints.insert(path, lines);
+                (self.response(request, true, json!({ "breakpoints": breakpoints })), vec![])
+            }
+            "configurationDone" => (self.response(request, true, json!({})), vec![]),
+            _ => {
+                let message = format!("`{command}` is not supported by the Arturo kernel");
+                let mut response = self.response(request, false, json!({}));
+                response["message"] = json!(message);
+                (response, vec![])
+            }
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn response(&mut self, request: &Value, success: bool, body: Value) -> Value {
+        json!({
+            "seq": self.next_seq(),
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": success,
+            "command": request["command"],
+            "body": body,
+        })
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Value {
+        json!({
+            "seq": self.next_seq(),
+            "type": "event",
+            "event": event,
+            "body": body,
+        })
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsConditionalBreakpoints": false,
+        "supportsEvaluateForHovers": false,
+        "supportsStepBack": false,
+        "supportsSetVariable": false,
+        "supportsTerminateRequest": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use serde_json::json;
+
+    #[test]
+    fn initialize_answers_and_announces() {
+        let mut debugger = Debugger::default();
+        let (response, events) =
+            debugger.handle(&json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }));
+        assert_eq!(response["success"], true);
+        assert_eq!(response["request_seq"], 1);
+        assert_eq!(response["body"]["supportsConfigurationDoneRequest"], true);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "initialized");
+        assert!(events[0]["seq"].as_u64() > response["seq"].as_u64());
+    }
+
+    #[test]
+    fn breakpoints_are_kept_unverified() {
+        let mut debugger = Debugger::default();
+        let (response, _) = debugger.handle(&json!({
+            "seq": 2,
+            "type": "request",
+            "command": "setBreakpoints",
+            "arguments": { "source": { "path": "cell-1.art" }, "breakpoints": [{ "line": 3 }, { "line": 5 }] },
+        }));
+        assert_eq!(response["success"], true);
+        assert_eq!(response["body"]["breakpoints"][1]["line"], 5);
+        assert_eq!(response["body"]["breakpoints"][1]["verified"], false);
+        assert_eq!(debugger.breakpoints["cell-1.art"], [3, 5]);
+    }
+
+    #[test]
+    fn other_commands_fail() {
+        let mut debugger = Debugger::default();
+        let (response, _) = debugger.handle(&json!({ "seq": 3, "type": "request", "command": "stepIn" }));
+        assert_eq!(response["success"], false);
+        assert_eq!(response["command"], "stepIn");
+
+        let (response, _) = debugger.handle(&json!({ "seq": 4, "type": "request", "command": "configurationDone" }));
+        assert_eq!(response["success"], true);
+    }
+}
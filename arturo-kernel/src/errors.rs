@@ -0,0 +1,306 @@
+//! Turning Arturo's error output into Jupyter `ename` / `evalue` / `traceback`.
+//!
+//! Arturo reports errors as a banner naming the error kind followed by the
+//! message and, when known, the offending line — either the boxed form
+//!
+//! ```text
+//! ══╡ Assertion Error ╞══════════════════════ <script>:3
+//!   assertion failed: x > 0
+//! ```
+//!
+//! or the older `key: | value` table form
+//!
+//! ```text
+//! >> Runtime | File: <script>
+//!      error: | Symbol not found: foo
+//!       line: | 3
+//! ```
+//!
+//! Line numbers refer to the program Arturo actually ran, so they are mapped
+//! back through the program's [`SourceMap`] to the cell and line they came
+//! from.
+
+use crate::sourcemap::{Location, SourceMap};
+
+#[derive(Debug, Clone)]
+pub struct ArturoError {
+    pub ename: String,
+    pub evalue: String,
+    pub traceback: Vec<String>,
+    /// The cell and line the error was reported at, if Arturo gave one.
+    pub location: Option<Location>,
+}
+
+/// Fields recovered from Arturo's error output.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Report {
+    kind: Option<String>,
+    message: Option<String>,
+    /// One-based line in the program Arturo ran.
+    line: Option<usize>,
+}
+
+impl ArturoError {
+    /// Build the error for a failed run of `program`, whose lines map back to
+    /// cells through `map`.
+    pub fn from_output(stderr: &str, stdout: &str, program: &str, map: &SourceMap) -> ArturoError {
+        let mut report = parse(stderr);
+        if report.kind.is_none() && report.message.is_none() {
+            report = parse(stdout);
+        }
+
+        // Unrecognised stderr is still about the error, but stdout without a
+        // banner is only what the cell printed.
+        let message = report
+            .message
+            .or_else(|| strip_ansi(stderr).lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_string))
+            .unwrap_or_else(|| "Arturo error".to_string());
+        let ename = error_name(report.kind.as_deref(), &message);
+        let location = report.line.and_then(|line| map.locate(line));
+
+        let mut traceback = vec![format!("{ename}: {message}")];
+        match (report.line, location) {
+            (Some(line), Some(Location { cell, line: cell_line })) => {
+                traceback.push(format!("  In [{cell}], line {cell_line}"));
+                if let Some(source) = program.lines().nth(line - 1) {
+                    traceback.push(format!("  {cell_line:>4} | {}", source.trim_end()));
+                }
+            }
+            (Some(line), None) => traceback.push(format!("  at line {line}")),
+            (None, _) => {}
+        }
+
+        ArturoError { ename, evalue: message, traceback, location }
+    }
+
+    /// An error reported by the kernel itself rather than by Arturo.
+    pub fn kernel(ename: &str, message: impl Into<String>) -> ArturoError {
+        let message = message.into();
+        ArturoError {
+            ename: ename.to_string(),
+            traceback: vec![format!("{ename}: {message}")],
+            evalue: message,
+            location: None,
+        }
+    }
+
+    /// A misused magic.
+    pub fn usage(message: impl Into<String>) -> ArturoError {
+        ArturoError::kernel("UsageError", message)
+    }
+}
+
+fn parse(output: &str) -> Report {
+    let text = strip_ansi(output);
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let mut report = Report::default();
+
+    for (i, line) in lines.iter().enumerate() {
+        if let (Some(open), Some(close)) = (line.find('╡'), line.find('╞')) {
+            if open < close {
+                report.kind = Some(line[open + '╡'.len_utf8()..close].trim().to_string());
+                let location = line[close..].trim_matches(|c: char| c == '╞' || c == '═' || c.is_whitespace());
+                report.line = report.line.or_else(|| trailing_line_number(location));
+                report.message = report.message.take().or_else(|| {
+                    lines[i + 1..]
+                        .iter()
+                        .find(|l| !l.chars().all(|c| matches!(c, '═' | '─' | '-' | '=')))
+                        .map(|l| l.to_string())
+                });
+                continue;
+            }
+        }
+
+        if let Some(rest) = line.strip_prefix(">>") {
+            let kind = rest.split('|').next().unwrap_or("").trim();
+            if !kind.is_empty() {
+                report.kind = Some(kind.to_string());
+            }
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('|') {
+            let key = key.trim().trim_end_matches(':').to_ascii_lowercase();
+            let value = value.trim();
+            match key.as_str() {
+                "error" | "message" if report.message.is_none() => {
+                    report.message = Some(value.to_string());
+                }
+                "error" | "message" => {
+                    let message = report.message.get_or_insert_with(String::new);
+                    message.push(' ');
+                    message.push_str(value);
+                }
+                "line" => report.line = report.line.or_else(|| value.parse().ok()),
+                _ => {}
+            }
+            continue;
+        }
+
+        if report.line.is_none() {
+            report.line = line_keyword_number(line);
+        }
+    }
+
+    report
+}
+
+/// `RuntimeError`-style name from Arturo's kind (`"Runtime"`, `"Type Error"`),
+/// refined by the message when the kind is only a generic category.
+fn error_name(kind: Option<&str>, message: &str) -> String {
+    let kind = kind.unwrap_or("").trim();
+    let generic = kind.is_empty()
+        || ["runtime", "runtime error", "program", "program error", "error"]
+            .contains(&kind.to_ascii_lowercase().as_str());
+
+    if generic {
+        let lower = message.to_ascii_lowercase();
+        if lower.contains("symbol not found") || lower.contains("undefined") {
+            return "UndefinedSymbolError".to_string();
+        }
+        if lower.contains("assert") {
+            return "AssertionError".to_string();
+        }
+        if lower.contains("cannot perform") || lower.contains("argument") && lower.contains("type") {
+            return "TypeError".to_string();
+        }
+        if kind.is_empty() {
+            return "ArturoError".to_string();
+        }
+    }
+
+    let mut name: String = kind
+        .split_whitespace()
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |c| c.to_uppercase().chain(chars).collect())
+        })
+        .collect();
+    if !name.ends_with("Error") {
+        name.push_str("Error");
+    }
+    name
+}
+
+/// `<script>:12` or `file.art:12` → 12
+fn trailing_line_number(location: &str) -> Option<usize> {
+    let (_, number) = location.rsplit_once(':')?;
+    number.trim().parse().ok()
+}
+
+/// The first number after the word "line" (`Line: 3`, `at line 3`).
+fn line_keyword_number(line: &str) -> Option<usize> {
+    let lower = line.to_ascii_lowercase();
+    let after = &lower[lower.find("line")? + 4..];
+    let digits: String = after
+        .trim_start_matches(|c: char| c == ':' || c.is_whitespace())
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // CSI sequence: ESC [ params final-byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArturoError;
+    use crate::sourcemap::{Location, SourceMap};
+
+    const PRELUDE: &str = "x: 1\ny: 2\nz: 3";
+    const CELL: &str = "a: 1\nprint foo\nb: 2";
+
+    /// Three preamble lines from cell 1, then cell 4, when `preamble` is set.
+    fn program(preamble: bool) -> (String, SourceMap) {
+        if !preamble {
+            return (CELL.to_string(), SourceMap::cell(4, CELL));
+        }
+        let mut map = SourceMap::default();
+        map.push(1, 2, PRELUDE);
+        map.push(4, 1, CELL);
+        (format!("{PRELUDE}\n{CELL}"), map)
+    }
+
+    fn from_output(stderr: &str, stdout: &str, preamble: bool) -> ArturoError {
+        let (program, map) = program(preamble);
+        ArturoError::from_output(stderr, stdout, &program, &map)
+    }
+
+    #[test]
+    fn boxed_banner_with_location() {
+        let stderr = "══╡ Assertion Error ╞══════════ <script>:5\n\n  assertion failed: x > 0\n";
+        let error = from_output(stderr, "", true);
+        assert_eq!(error.ename, "AssertionError");
+        assert_eq!(error.evalue, "assertion failed: x > 0");
+        assert_eq!(error.traceback[1], "  In [4], line 2");
+        assert_eq!(error.traceback[2], "     2 | print foo");
+    }
+
+    #[test]
+    fn table_form_with_generic_kind_is_refined() {
+        let stderr = ">> Runtime | File: <script>\n     error: | Symbol not found: foo\n      line: | 2\n";
+        let error = from_output(stderr, "", false);
+        assert_eq!(error.ename, "UndefinedSymbolError");
+        assert_eq!(error.evalue, "Symbol not found: foo");
+        assert_eq!(error.traceback[1], "  In [4], line 2");
+    }
+
+    #[test]
+    fn ansi_colours_are_ignored() {
+        let stderr = "\x1b[31m══╡ Runtime Error ╞═══ <script>\x1b[0m\n\n  cannot perform: add\n";
+        let error = from_output(stderr, "", false);
+        assert_eq!(error.ename, "TypeError");
+        assert_eq!(error.evalue, "cannot perform: add");
+    }
+
+    #[test]
+    fn line_inside_preamble() {
+        let stderr = ">> Runtime | File: <script>\n     error: | boom\n      line: | 2\n";
+        let error = from_output(stderr, "", true);
+        assert_eq!(error.ename, "RuntimeError");
+        assert_eq!(error.location, Some(Location { cell: 1, line: 3 }));
+        assert_eq!(error.traceback[1], "  In [1], line 3");
+        assert_eq!(error.traceback[2], "     3 | y: 2");
+    }
+
+    #[test]
+    fn unrecognised_output_uses_first_line() {
+        let error = from_output("something broke\ndetails", "", false);
+        assert_eq!(error.ename, "ArturoError");
+        assert_eq!(error.evalue, "something broke");
+        assert_eq!(error.traceback.len(), 1);
+    }
+
+    #[test]
+    fn unrecognised_stdout_is_not_the_message() {
+        let error = from_output("", "hello\n", false);
+        assert_eq!(error.ename, "ArturoError");
+        assert_eq!(error.evalue, "Arturo error");
+    }
+
+    #[test]
+    fn falls_back_to_stdout() {
+        let stdout = "══╡ Type Error ╞═══\n  wrong argument type\n";
+        let error = from_output("", stdout, false);
+        assert_eq!(error.ename, "TypeError");
+        assert_eq!(error.evalue, "wrong argument type");
+    }
+}
@@ -0,0 +1,404 @@
+//! Persistent execution history for `history_request`.
+//!
+//! Every stored cell is appended as one JSON line to
+//! `<jupyter data dir>/arturo-kernel/history.jsonl` (or the file named by
+//! `ARTURO_KERNEL_HISTORY`). Each kernel start is a new numbered session, so
+//! front-ends can recall cells from earlier runs with `range`, `tail` and
+//! `search` queries. Output is only kept when configured, and both outputs
+//! and the number of entries are capped so the file stays small.
+//!
+//! Several kernels may share the file. A lock file next to it serialises
+//! writes to it and holds the last session number handed out, so kernels
+//! running side by side never share a session.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+/// Entries kept in memory, and in the file once the kernel starts again.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Bytes of a cell's output saved with it.
+const MAX_OUTPUT: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Sequential session number, as used by the history protocol.
+    session: u32,
+    /// The kernel session id (`header.session`) that recorded the entry.
+    session_id: String,
+    /// Execution count of the cell within its session.
+    line: u32,
+    input: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    path: Option<PathBuf>,
+    session: u32,
+    session_id: String,
+    /// Whether `record` keeps a cell's output.
+    with_output: bool,
+    entries: Vec<Entry>,
+}
+
+impl History {
+    /// Load the most recent entries of the history file, dropping older ones
+    /// from it, and start a new session.
+    pub fn open(session_id: &str, with_output: bool) -> History {
+        History::open_at(history_path(), session_id, with_output)
+    }
+
+    fn open_at(path: Option<PathBuf>, session_id: &str, with_output: bool) -> History {
+        let loaded = path.as_deref().map(|path| {
+            load(path).unwrap_or_else(|e| {
+                eprintln!("[arturo-kernel] Could not read history from {}: {e}", path.display());
+                (1, Vec::new())
+            })
+        });
+        let (session, entries) = loaded.unwrap_or((1, Vec::new()));
+        History { path, session, session_id: session_id.to_string(), with_output, entries }
+    }
+
+    /// Number later entries as a new session, as after a restart.
+    pub fn new_session(&mut self) {
+        let next = self.path.as_deref().map(|path| with_lock(path, |lock| next_session(lock, &self.entries)));
+        self.session = match next {
+            Some(Ok(session)) => session,
+            Some(Err(e)) => {
+                eprintln!("[arturo-kernel] Could not number a new history session: {e}");
+                self.session + 1
+            }
+            None => self.session + 1,
+        };
+    }
+
+    pub fn record(&mut self, line: u32, input: &str, output: Option<&str>) {
+        let entry = Entry {
+            session: self.session,
+            session_id: self.session_id.clone(),
+            line,
+            input: input.to_string(),
+            output: output.filter(|o| self.with_output && !o.is_empty()).map(truncate),
+        };
+
+        if let Some(path) = &self.path {
+            let appended = with_lock(path, |_| {
+                let mut line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+                line.push('\n');
+                OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())
+            });
+            if let Err(e) = appended {
+                eprintln!("[arturo-kernel] Could not write history to {}: {e}", path.display());
+            }
+        }
+
+        self.entries.push(entry);
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Build the content of a `history_reply` for a `history_request`.
+    pub fn reply(&self, request: &Value) -> Value {
+        let with_output = request["output"].as_bool().unwrap_or(false);
+        let n = request["n"].as_u64().map(|n| n as usize);
+
+        let selected: Vec<&Entry> = match request["hist_access_type"].as_str().unwrap_or("tail") {
+            "range" => {
+                let session = request["session"].as_i64().unwrap_or(0);
+                let start = request["start"].as_u64().unwrap_or(0) as u32;
+                let stop = request["stop"].as_u64().map(|s| s as u32);
+                self.range(session, start, stop)
+            }
+            "search" => {
+                let pattern = request["pattern"].as_str().unwrap_or("*");
+                let unique = request["unique"].as_bool().unwrap_or(false);
+                self.search(pattern, n, unique)
+            }
+            _ => self.tail(n.unwrap_or(10)),
+        };
+
+        let history: Vec<Value> = selected
+            .into_iter()
+            .map(|e| {
+                if with_output {
+                    json!([e.session, e.line, [e.input, e.output.as_deref()]])
+                } else {
+                    json!([e.session, e.line, e.input])
+                }
+            })
+            .collect();
+
+        json!({ "status": "ok", "history": history })
+    }
+
+    /// Lines `start..stop` of a session. Session `0` is the current one and
+    /// negative numbers count back from it; a missing `stop` means "to the end".
+    fn range(&self, session: i64, start: u32, stop: Option<u32>) -> Vec<&Entry> {
+        let session = if session <= 0 { self.session as i64 + session } else { session };
+        self.entries
+            .iter()
+            .filter(|e| e.session as i64 == session)
+            .filter(|e| e.line >= start && stop.is_none_or(|stop| e.line < stop))
+            .collect()
+    }
+
+    fn tail(&self, n: usize) -> Vec<&Entry> {
+        self.entries[self.entries.len().saturating_sub(n)..].iter().collect()
+    }
+
+    /// The most recent `n` (or all) entries whose input matches the glob
+    /// `pattern`, oldest first; with `unique`, repeated inputs appear once.
+    fn search(&self, pattern: &str, n: Option<usize>, unique: bool) -> Vec<&Entry> {
+        let mut seen = HashSet::new();
+        let mut found: Vec<&Entry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| glob_match(pattern, &e.input))
+            .filter(|e| !unique || seen.insert(e.input.as_str()))
+            .take(n.unwrap_or(usize::MAX))
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+/// `output` cut to [`MAX_OUTPUT`] bytes.
+fn truncate(output: &str) -> String {
+    if output.len() <= MAX_OUTPUT {
+        return output.to_string();
+    }
+    let end = (0..=MAX_OUTPUT).rev().find(|&i| output.is_char_boundary(i)).unwrap_or(0);
+    format!("{}…", &output[..end])
+}
+
+/// Run `f` holding the lock on the history file at `path`, with the lock
+/// file open for `f` to read or write.
+fn with_lock<T>(path: &Path, f: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lock = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.with_extension("jsonl.lock"))?;
+    lock.lock()?;
+    f(&mut lock)
+}
+
+/// Read the history file, trim it to [`MAX_ENTRIES`], and hand out a new
+/// session number.
+fn load(path: &Path) -> io::Result<(u32, Vec<Entry>)> {
+    with_lock(path, |lock| {
+        let text = match fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            read => read?,
+        };
+        let mut entries: Vec<Entry> = text.lines().filter_map(|l| serde_json::from_str(l).ok()).collect();
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+            if let Err(e) = rewrite(path, &entries) {
+                eprintln!("[arturo-kernel] Could not trim history in {}: {e}", path.display());
+            }
+        }
+        Ok((next_session(lock, &entries)?, entries))
+    })
+}
+
+/// The session number after the last one handed out, kept in the `lock`
+/// file, or after the last one in `entries` if that is later.
+fn next_session(lock: &mut File, entries: &[Entry]) -> io::Result<u32> {
+    let mut last = String::new();
+    lock.rewind()?;
+    lock.read_to_string(&mut last)?;
+    let last = last.trim().parse().unwrap_or(0).max(entries.iter().map(|e| e.session).max().unwrap_or(0));
+    lock.set_len(0)?;
+    lock.rewind()?;
+    write!(lock, "{}", last + 1)?;
+    Ok(last + 1)
+}
+
+/// Replace the history file with `entries`.
+fn rewrite(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut text = String::new();
+    for entry in entries {
+        text.push_str(&serde_json::to_string(entry).map_err(io::Error::other)?);
+        text.push('\n');
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("ARTURO_KERNEL_HISTORY") {
+        return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+    jupyter_data_dir().map(|dir| dir.join("arturo-kernel").join("history.jsonl"))
+}
+
+/// Jupyter's per-user data directory, following `jupyter --data-dir`.
+fn jupyter_data_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("JUPYTER_DATA_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        return env::var_os("APPDATA").map(|d| PathBuf::from(d).join("jupyter"));
+    }
+    let home = PathBuf::from(env::var_os("HOME")?);
+    if cfg!(target_os = "macos") {
+        return Some(home.join("Library").join("Jupyter"));
+    }
+    let data = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".local").join("share"));
+    Some(data.join("jupyter"))
+}
+
+/// Glob matching with `*` (any run) and `?` (any one character), the pattern
+/// language of `history_request` searches.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, History, MAX_ENTRIES, MAX_OUTPUT};
+    use crate::testing::TempDir;
+    use serde_json::json;
+    use std::fs;
+
+    /// An in-memory history in its third session, with two cells in each.
+    fn sessions(with_output: bool) -> History {
+        let mut history =
+            History { path: None, session: 1, session_id: "s".to_string(), with_output, entries: Vec::new() };
+        for session in 1..=3 {
+            if session > 1 {
+                history.new_session();
+            }
+            history.record(1, &format!("a{session}"), Some("out\n"));
+            history.record(2, &format!("b{session}"), None);
+        }
+        history
+    }
+
+    fn inputs(history: &History, request: serde_json::Value) -> Vec<serde_json::Value> {
+        history.reply(&request)["history"].as_array().unwrap().iter().map(|e| e[2].clone()).collect()
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("print*", "print 1"));
+        assert!(glob_match("*x*", "a x b"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("*.art*", "load lib.art\nprint 1"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("print*", "x: print"));
+        assert!(glob_match("é*", "émile"));
+    }
+
+    #[test]
+    fn range_counts_sessions_back_from_the_current_one() {
+        let history = sessions(false);
+        let range = |session: i64| inputs(&history, json!({ "hist_access_type": "range", "session": session }));
+        assert_eq!(range(0), [json!("a3"), json!("b3")]);
+        assert_eq!(range(-1), [json!("a2"), json!("b2")]);
+        assert_eq!(range(1), [json!("a1"), json!("b1")]);
+        assert!(range(-5).is_empty());
+
+        let request = json!({ "hist_access_type": "range", "session": -2, "start": 2, "stop": 3 });
+        assert_eq!(inputs(&history, request), [json!("b1")]);
+    }
+
+    #[test]
+    fn tail_and_search() {
+        let history = sessions(false);
+        assert_eq!(inputs(&history, json!({ "n": 2 })), [json!("a3"), json!("b3")]);
+        let request = json!({ "hist_access_type": "search", "pattern": "a*", "n": 2 });
+        assert_eq!(inputs(&history, request), [json!("a2"), json!("a3")]);
+    }
+
+    #[test]
+    fn output_is_only_kept_when_enabled() {
+        let request = json!({ "hist_access_type": "tail", "n": 1, "output": true });
+        let mut history = sessions(false);
+        history.record(3, "c", Some("out\n"));
+        assert_eq!(history.reply(&request)["history"][0][2], json!(["c", null]));
+
+        let mut history = sessions(true);
+        history.record(3, "c", Some("out\n"));
+        assert_eq!(history.reply(&request)["history"][0][2], json!(["c", "out\n"]));
+
+        history.record(4, "d", Some(&"é".repeat(MAX_OUTPUT)));
+        let output = history.entries.last().unwrap().output.clone().unwrap();
+        assert!(output.len() <= MAX_OUTPUT + '…'.len_utf8());
+        assert!(output.ends_with("é…"));
+    }
+
+    #[test]
+    fn kernels_sharing_the_file_get_their_own_sessions() {
+        let dir = TempDir::new();
+        let path = dir.path().join("history.jsonl");
+        let mut first = History::open_at(Some(path.clone()), "a", false);
+        let mut second = History::open_at(Some(path.clone()), "b", false);
+        assert_eq!((first.session, second.session), (1, 2));
+        first.record(1, "from a", None);
+        second.record(1, "from b", None);
+
+        first.new_session();
+        assert_eq!(first.session, 3);
+        let third = History::open_at(Some(path), "c", false);
+        assert_eq!(third.session, 4);
+        let inputs: Vec<_> = third.entries.iter().map(|e| (e.session, e.input.as_str())).collect();
+        assert_eq!(inputs, [(1, "from a"), (2, "from b")]);
+    }
+
+    #[test]
+    fn opening_trims_the_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("history.jsonl");
+        let lines: String = (0..MAX_ENTRIES + 5)
+            .map(|i| format!("{}\n", json!({ "session": 7, "session_id": "s", "line": i, "input": "x" })))
+            .collect();
+        fs::write(&path, lines).unwrap();
+
+        let history = History::open_at(Some(path.clone()), "t", false);
+        assert_eq!(history.session, 8);
+        assert_eq!(history.entries.len(), MAX_ENTRIES);
+        assert_eq!(history.entries[0].line, 5);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), MAX_ENTRIES);
+    }
+}
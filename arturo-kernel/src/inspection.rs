@@ -0,0 +1,127 @@
+//! `inspect_request` support: builtin documentation and user definitions.
+
+use crate::{
+    builtins::{self, Builtin},
+    completion::{byte_offset, word_bounds},
+    preamble::Preamble,
+};
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+/// Build the content of an `inspect_reply` for the name under `cursor_pos`
+/// (counted in code points).
+pub fn inspect(code: &str, cursor_pos: usize, preamble: &Preamble) -> Value {
+    let cursor = byte_offset(code, cursor_pos);
+    let (start, end) = word_bounds(code, cursor);
+    let name = &code[start..end];
+
+    let mut plain = Vec::new();
+    let mut markdown = Vec::new();
+
+    if let Some(source) = preamble.definition(name) {
+        plain.push(format!("{name} — defined in this session:\n\n{source}"));
+        markdown.push(format!("**{name}** — defined in this session\n\n```arturo\n{source}\n```"));
+    }
+    if let Some(builtin) = builtins::all().get(name) {
+        plain.push(builtin_plain(builtin));
+        markdown.push(builtin_markdown(builtin));
+    }
+
+    if plain.is_empty() {
+        return json!({ "status": "ok", "found": false, "data": {}, "metadata": {} });
+    }
+
+    json!({
+        "status": "ok",
+        "found": true,
+        "data": {
+            "text/plain": plain.join("\n\n"),
+            "text/markdown": markdown.join("\n\n---\n\n"),
+        },
+        "metadata": {}
+    })
+}
+
+fn builtin_plain(builtin: &Builtin) -> String {
+    let mut out = format!("{}\n\n{}\n", builtin.signature, builtin.description);
+    if !builtin.params.is_empty() {
+        out.push_str("\nArguments:\n");
+        for param in &builtin.params {
+            writeln!(out, "  {}  {}", param.name, param.ty).ok();
+        }
+    }
+    if !builtin.attributes.is_empty() {
+        out.push_str("\nAttributes:\n");
+        for attr in &builtin.attributes {
+            writeln!(out, "  .{}  {}  {}", attr.name, attr.ty, attr.description).ok();
+        }
+    }
+    write!(out, "\nReturns: {}", builtin.returns).ok();
+    if let Some(module) = &builtin.module {
+        write!(out, "\nModule: {module}").ok();
+    }
+    out
+}
+
+fn builtin_markdown(builtin: &Builtin) -> String {
+    let mut out = format!("```arturo\n{}\n```\n\n{}\n", builtin.signature, builtin.description);
+    if !builtin.params.is_empty() {
+        out.push_str("\n| Argument | Type |\n|---|---|\n");
+        for param in &builtin.params {
+            writeln!(out, "| `{}` | `{}` |", param.name, param.ty).ok();
+        }
+    }
+    if !builtin.attributes.is_empty() {
+        out.push_str("\n| Attribute | Type | Description |\n|---|---|---|\n");
+        for attr in &builtin.attributes {
+            writeln!(out, "| `.{}` | `{}` | {} |", attr.name, attr.ty, attr.description).ok();
+        }
+    }
+    write!(out, "\n**Returns:** `{}`", builtin.returns).ok();
+    if let Some(module) = &builtin.module {
+        write!(out, " · **Module:** {module}").ok();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::inspect;
+    use crate::{preamble::Preamble, testing::session};
+
+    #[test]
+    fn builtin() {
+        let reply = inspect("print x", 2, &Preamble::default());
+        assert_eq!(reply["found"], true);
+        let plain = reply["data"]["text/plain"].as_str().unwrap();
+        assert!(plain.starts_with("print"));
+        assert!(plain.contains("Returns:"));
+        assert!(reply["data"]["text/markdown"].as_str().unwrap().starts_with("```arturo\nprint"));
+    }
+
+    #[test]
+    fn session_symbol() {
+        let reply = inspect("double 4", 0, &session("double: function [n] [n * 2]"));
+        assert_eq!(reply["found"], true);
+        assert_eq!(
+            reply["data"]["text/plain"],
+            "double — defined in this session:\n\ndouble: function [n] [n * 2]"
+        );
+    }
+
+    #[test]
+    fn session_symbol_shadowing_a_builtin() {
+        let reply = inspect("max", 3, &session("max: 10"));
+        let plain = reply["data"]["text/plain"].as_str().unwrap();
+        assert!(plain.starts_with("max — defined in this session:\n\nmax: 10\n\nmax"));
+        assert!(reply["data"]["text/markdown"].as_str().unwrap().contains("\n\n---\n\n"));
+    }
+
+    #[test]
+    fn unknown_name() {
+        let reply = inspect("frobnicate 1", 4, &Preamble::default());
+        assert_eq!(reply["found"], false);
+        assert_eq!(reply["data"], serde_json::json!({}));
+        assert_eq!(inspect("", 0, &Preamble::default())["found"], false);
+    }
+}
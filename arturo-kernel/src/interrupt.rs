@@ -0,0 +1,94 @@
+//! Interrupting a running cell.
+//!
+//! Arturo processes run in their own process group, so a signal also reaches
+//! anything the cell started. The control thread only raises the
+//! [`Interrupt`] flag; the loop reading the cell's output (see
+//! [`Watchdog`](crate::limits::Watchdog)) sends SIGINT, then SIGTERM and
+//! SIGKILL while the cell keeps running past the configured delays.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const NONE: u8 = 0;
+const INTERRUPT: u8 = 1;
+/// A kill that ends the session; it stays raised until [`Interrupt::reset`].
+const DISCARD: u8 = 2;
+
+/// An interrupt requested by the front-end, not yet acted on.
+#[derive(Debug, Default)]
+pub struct Interrupt(AtomicU8);
+
+impl Interrupt {
+    pub fn request(&self) {
+        self.0.fetch_max(INTERRUPT, Ordering::SeqCst);
+    }
+
+    /// Stop the running cell at once, without the SIGINT and SIGTERM steps,
+    /// because the session is being thrown away: nothing is restored after
+    /// the kill, and cells started before [`Interrupt::reset`] are killed too.
+    pub fn discard(&self) {
+        self.0.store(DISCARD, Ordering::SeqCst);
+    }
+
+    /// Whether a request is waiting to be acted on.
+    pub fn pending(&self) -> bool {
+        self.0.load(Ordering::SeqCst) != NONE
+    }
+
+    pub fn discarded(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == DISCARD
+    }
+
+    /// Forget a request made while no cell was running. A discard stays.
+    pub fn clear(&self) {
+        self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| (v != DISCARD).then_some(NONE)).ok();
+    }
+
+    /// Forget every request, a discard included, once the session was replaced.
+    pub fn reset(&self) {
+        self.0.store(NONE, Ordering::SeqCst);
+    }
+
+    pub fn take(&self) -> Option<Signal> {
+        let (Ok(taken) | Err(taken)) =
+            self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| (v != DISCARD).then_some(NONE));
+        match taken {
+            INTERRUPT => Some(Signal::Interrupt),
+            DISCARD => Some(Signal::Kill),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+/// Send `signal` to the process group led by `pid`. On Windows every signal
+/// terminates the process itself.
+pub fn signal(pid: u32, signal: Signal) {
+    #[cfg(unix)]
+    unsafe {
+        let signal = match signal {
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        };
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+    #[cfg(windows)]
+    unsafe {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::Threading::{
+            OpenProcess, TerminateProcess, PROCESS_TERMINATE,
+        };
+        let _ = signal;
+        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if handle != 0 {
+            TerminateProcess(handle, 1);
+            CloseHandle(handle);
+        }
+    }
+}
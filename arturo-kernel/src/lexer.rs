@@ -0,0 +1,396 @@
+//! Minimal Arturo lexer.
+//!
+//! Covers just enough of Arturo's lexical grammar for the kernel to tell code
+//! apart from string literals and comments and to follow `[ ]` / `( )`
+//! nesting: `"…"` strings with escapes, `{ }` multiline strings (which nest),
+//! `{: :}` verbatim strings, `« »` literals, `` `c` `` characters and `;`
+//! comments. Everything else is split into words, labels, numbers and symbols.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// `name`, `even?`, `to-upper`
+    Word,
+    /// `name:` — the trailing colon is part of the token
+    Label,
+    /// `'name`
+    Literal,
+    /// `:integer`
+    Type,
+    /// `.lines`, `.with:`
+    Attribute,
+    Number,
+    /// Any string-like literal: `"…"`, `{…}`, `{:…:}`, `«…»`
+    String,
+    /// `` `c` ``
+    Char,
+    /// `; …` up to (not including) the end of the line
+    Comment,
+    /// Operators and other punctuation: `+`, `->`, `#`, `@`, …
+    Symbol,
+    /// `[` or `(`
+    Open,
+    /// `]` or `)` — or a `}` / `»` that does not close any literal
+    Close,
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range of the token in the source.
+    pub start: usize,
+    pub end: usize,
+    /// Zero-based line on which the token starts.
+    pub line: usize,
+}
+
+impl Token {
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.start..self.end]
+    }
+}
+
+/// A string-like literal runs to the end of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unterminated {
+    /// Zero-based line on which the literal starts.
+    pub line: usize,
+}
+
+pub fn tokenize(src: &str) -> Result<Vec<Token>, Unterminated> {
+    let mut lexer = Lexer { src, pos: 0, line: 0 };
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.next_token()? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.src[self.pos..].chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat_while(&mut self, pred: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+    }
+
+    fn eat_word(&mut self) {
+        loop {
+            self.eat_while(is_word_char);
+            // Hyphenated names (`to-upper`) continue only into another letter,
+            // so `x-1` still lexes as `x`, `-`, `1`.
+            if self.peek() == Some('-') && self.peek_second().is_some_and(char::is_alphabetic) {
+                self.bump();
+                continue;
+            }
+            break;
+        }
+        if self.peek() == Some('?') {
+            self.bump();
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, Unterminated> {
+        self.eat_while(|c| c != '\n' && c.is_whitespace());
+
+        let start = self.pos;
+        let line = self.line;
+        let Some(c) = self.bump() else {
+            return Ok(None);
+        };
+
+        let kind = match c {
+            '\n' => TokenKind::Newline,
+            ';' => {
+                self.eat_while(|c| c != '\n');
+                TokenKind::Comment
+            }
+            '"' => {
+                self.quoted_string(line)?;
+                TokenKind::String
+            }
+            '{' if self.peek() == Some(':') => {
+                self.bump();
+                self.verbatim_string(line)?;
+                TokenKind::String
+            }
+            '{' => {
+                self.curly_string(line)?;
+                TokenKind::String
+            }
+            '«' => {
+                self.eat_until('»', line)?;
+                TokenKind::String
+            }
+            '`' => {
+                self.bump();
+                if self.peek() == Some('`') {
+                    self.bump();
+                }
+                TokenKind::Char
+            }
+            '[' | '(' => TokenKind::Open,
+            ']' | ')' | '}' | '»' => TokenKind::Close,
+            '\'' if self.peek().is_some_and(is_word_start) => {
+                self.eat_word();
+                TokenKind::Literal
+            }
+            ':' if self.peek().is_some_and(is_word_start) => {
+                self.eat_word();
+                TokenKind::Type
+            }
+            '.' if self.peek().is_some_and(is_word_start) => {
+                self.eat_word();
+                if self.peek() == Some(':') {
+                    self.bump();
+                }
+                TokenKind::Attribute
+            }
+            c if c.is_ascii_digit() => {
+                self.eat_while(|c| c.is_ascii_digit() || c == '_');
+                if self.peek() == Some('.') && self.peek_second().is_some_and(|c| c.is_ascii_digit()) {
+                    self.bump();
+                    self.eat_while(|c| c.is_ascii_digit());
+                }
+                TokenKind::Number
+            }
+            c if is_word_start(c) => {
+                self.eat_word();
+                if self.peek() == Some(':') && self.peek_second() != Some(':') {
+                    self.bump();
+                    TokenKind::Label
+                } else {
+                    TokenKind::Word
+                }
+            }
+            _ => {
+                self.eat_while(is_symbol_char);
+                TokenKind::Symbol
+            }
+        };
+
+        Ok(Some(Token { kind, start, end: self.pos, line }))
+    }
+
+    fn quoted_string(&mut self, line: usize) -> Result<(), Unterminated> {
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(()),
+                Some('\\') => {
+                    self.bump();
+                }
+                Some(_) => {}
+                None => return Err(Unterminated { line }),
+            }
+        }
+    }
+
+    fn curly_string(&mut self, line: usize) -> Result<(), Unterminated> {
+        let mut depth = 1;
+        loop {
+            match self.bump() {
+                Some('{') => depth += 1,
+                Some('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+                None => return Err(Unterminated { line }),
+            }
+        }
+    }
+
+    fn verbatim_string(&mut self, line: usize) -> Result<(), Unterminated> {
+        loop {
+            match self.bump() {
+                Some(':') if self.peek() == Some('}') => {
+                    self.bump();
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => return Err(Unterminated { line }),
+            }
+        }
+    }
+
+    fn eat_until(&mut self, close: char, line: usize) -> Result<(), Unterminated> {
+        loop {
+            match self.bump() {
+                Some(c) if c == close => return Ok(()),
+                Some(_) => {}
+                None => return Err(Unterminated { line }),
+            }
+        }
+    }
+}
+
+fn is_word_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_symbol_char(c: char) -> bool {
+    !(c.is_whitespace()
+        || is_word_char(c)
+        || matches!(c, ';' | '"' | '{' | '}' | '«' | '»' | '`' | '[' | ']' | '(' | ')' | '\''))
+}
+
+// ── Cell completeness ────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Completeness {
+    Complete,
+    /// More input is needed; the payload is the suggested indent for the next line.
+    Incomplete(String),
+    /// A closer with no matching opener, or `]` closing a `(`.
+    Invalid,
+}
+
+/// Decide whether `code` can be submitted as-is, for `is_complete_request`.
+pub fn completeness(code: &str) -> Completeness {
+    let tokens = match tokenize(code) {
+        Ok(tokens) => tokens,
+        Err(_) => return Completeness::Incomplete(String::new()),
+    };
+
+    let mut open: Vec<Token> = Vec::new();
+    for token in tokens {
+        match token.kind {
+            TokenKind::Open => open.push(token),
+            TokenKind::Close => {
+                let closes = match token.text(code) {
+                    "]" => "[",
+                    ")" => "(",
+                    _ => return Completeness::Invalid,
+                };
+                match open.pop() {
+                    Some(opener) if opener.text(code) == closes => {}
+                    _ => return Completeness::Invalid,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match open.last() {
+        None => Completeness::Complete,
+        Some(innermost) => {
+            let line = code.lines().nth(innermost.line).unwrap_or("");
+            let indent: String = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+            Completeness::Incomplete(indent + "    ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{completeness, tokenize, Completeness, TokenKind, Unterminated};
+
+    fn kinds(src: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(src).unwrap().into_iter().map(|t| (t.kind, t.text(src))).collect()
+    }
+
+    #[test]
+    fn words_labels_and_symbols() {
+        assert_eq!(
+            kinds("to-upper: even? x-1 'sym :integer .with:"),
+            [
+                (TokenKind::Label, "to-upper:"),
+                (TokenKind::Word, "even?"),
+                (TokenKind::Word, "x"),
+                (TokenKind::Symbol, "-"),
+                (TokenKind::Number, "1"),
+                (TokenKind::Literal, "'sym"),
+                (TokenKind::Type, ":integer"),
+                (TokenKind::Attribute, ".with:"),
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_quote_stays_in_the_string() {
+        assert_eq!(kinds(r#""say \"hi\" [" ]"#), [(TokenKind::String, r#""say \"hi\" [""#), (TokenKind::Close, "]")]);
+    }
+
+    #[test]
+    fn curly_strings_nest() {
+        assert_eq!(kinds("{a {b} [}"), [(TokenKind::String, "{a {b} [}")]);
+        assert_eq!(kinds("{: } ] :}"), [(TokenKind::String, "{: } ] :}")]);
+        assert_eq!(kinds("«[ ( »"), [(TokenKind::String, "«[ ( »")]);
+    }
+
+    #[test]
+    fn char_literals_and_comments() {
+        assert_eq!(kinds("`[` `]`"), [(TokenKind::Char, "`[`"), (TokenKind::Char, "`]`")]);
+        assert_eq!(
+            kinds("x ; [ not open\ny"),
+            [
+                (TokenKind::Word, "x"),
+                (TokenKind::Comment, "; [ not open"),
+                (TokenKind::Newline, "\n"),
+                (TokenKind::Word, "y"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_literals() {
+        assert_eq!(tokenize("x: 1\ns: \"open").unwrap_err(), Unterminated { line: 1 });
+        assert_eq!(tokenize("{ {a}").unwrap_err(), Unterminated { line: 0 });
+        assert_eq!(tokenize("{: a }").unwrap_err(), Unterminated { line: 0 });
+    }
+
+    #[test]
+    fn complete_cells() {
+        assert_eq!(completeness("print 1"), Completeness::Complete);
+        assert_eq!(completeness("f: function [x] [\n    x\n]"), Completeness::Complete);
+        assert_eq!(completeness("print \"]\" ; ["), Completeness::Complete);
+        assert_eq!(completeness(""), Completeness::Complete);
+    }
+
+    #[test]
+    fn stray_or_mismatched_closers_are_invalid() {
+        assert_eq!(completeness("print 1 }"), Completeness::Invalid);
+        assert_eq!(completeness("x)"), Completeness::Invalid);
+        assert_eq!(completeness("(1 + 2]"), Completeness::Invalid);
+        assert_eq!(completeness("x »"), Completeness::Invalid);
+    }
+
+    #[test]
+    fn incomplete_cells_suggest_an_indent() {
+        assert_eq!(completeness("loop 1..3 'i ["), Completeness::Incomplete("    ".to_string()));
+        assert_eq!(
+            completeness("f: function [x] [\n    if x [\n        print x"),
+            Completeness::Incomplete("        ".to_string())
+        );
+        assert_eq!(completeness("\tmap xs (\n"), Completeness::Incomplete("\t    ".to_string()));
+        assert_eq!(completeness("s: {\n  unfinished"), Completeness::Incomplete(String::new()));
+    }
+}
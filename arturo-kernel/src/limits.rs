@@ -0,0 +1,302 @@
+//! Per-cell resource limits, and interrupts.
+//!
+//! The wall-clock timeout and the output cap are enforced by the kernel while
+//! it reads a cell's output: once either is exceeded the Arturo process group
+//! is killed. The memory limit is an address-space rlimit set on the child
+//! (Unix only), so Arturo itself fails its allocation and exits. The same
+//! [`Watchdog`] carries out interrupt requests.
+
+use crate::{
+    errors::ArturoError,
+    interrupt::{self, Interrupt, Signal},
+    output::FLUSH_INTERVAL,
+};
+use std::{
+    fmt,
+    process::{Command, ExitStatus},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    /// Megabytes of address space for an Arturo process.
+    pub memory_mb: Option<u64>,
+    /// Bytes of stdout plus stderr per cell.
+    pub output_bytes: Option<usize>,
+    /// How long an interrupted cell may keep running before SIGTERM.
+    pub sigterm_delay: Duration,
+    /// How long after SIGTERM before SIGKILL.
+    pub sigkill_delay: Duration,
+}
+
+/// The limit a cell ran into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Time(Duration),
+    Output(usize),
+    Memory(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Time(timeout) => write!(f, "the cell exceeded its {:.1?} time limit", timeout),
+            Limit::Output(bytes) => write!(f, "the cell exceeded its {bytes}-byte output limit"),
+            Limit::Memory(mb) => write!(f, "Arturo exceeded its {mb} MB memory limit"),
+        }
+    }
+}
+
+impl Limit {
+    pub fn error(&self) -> ArturoError {
+        let ename = match self {
+            Limit::Time(_) => "TimeoutError",
+            Limit::Output(_) => "OutputLimitError",
+            Limit::Memory(_) => "MemoryError",
+        };
+        let mut message = self.to_string();
+        if let Some(first) = message.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        ArturoError::kernel(ename, message)
+    }
+}
+
+/// Output captured from an Arturo process the kernel stopped at a limit.
+#[derive(Debug)]
+pub struct Stopped {
+    pub stdout: String,
+    pub limit: Limit,
+}
+
+impl Limits {
+    /// Put `cmd`'s child in its own process group and apply the memory limit.
+    pub fn apply(&self, cmd: &mut Command) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+            if let Some(mb) = self.memory_mb {
+                let bytes = mb.saturating_mul(1024 * 1024) as libc::rlim_t;
+                // SAFETY: only calls setrlimit, which is async-signal-safe.
+                unsafe {
+                    cmd.pre_exec(move || {
+                        let limit = libc::rlimit { rlim_cur: bytes, rlim_max: bytes };
+                        if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+}
+
+/// Tracks one cell against the time and output limits, and escalates an
+/// interrupt while the cell keeps running.
+#[derive(Debug)]
+pub struct Watchdog {
+    limits: Limits,
+    deadline: Option<Instant>,
+    output: usize,
+    interrupt: Arc<Interrupt>,
+    /// When the interrupt was first acted on, and the last signal sent.
+    interrupted: Option<(Instant, Signal)>,
+}
+
+impl Watchdog {
+    pub fn start(limits: &Limits, interrupt: &Arc<Interrupt>) -> Watchdog {
+        interrupt.clear();
+        Watchdog {
+            limits: *limits,
+            deadline: limits.timeout.and_then(|t| Instant::now().checked_add(t)),
+            output: 0,
+            interrupt: Arc::clone(interrupt),
+            interrupted: None,
+        }
+    }
+
+    /// Signal the process group of `pid` as an interrupt request demands:
+    /// SIGINT at once, SIGTERM and then SIGKILL as the delays pass. A
+    /// discard skips straight to SIGKILL.
+    pub fn escalate(&mut self, pid: u32) {
+        match self.interrupt.take() {
+            Some(Signal::Kill) if !matches!(self.interrupted, Some((_, Signal::Kill))) => {
+                interrupt::signal(pid, Signal::Kill);
+                self.interrupted = Some((Instant::now(), Signal::Kill));
+                return;
+            }
+            Some(_) if self.interrupted.is_none() => {
+                interrupt::signal(pid, Signal::Interrupt);
+                self.interrupted = Some((Instant::now(), Signal::Interrupt));
+                return;
+            }
+            _ => {}
+        }
+        let Some((since, sent)) = self.interrupted else {
+            return;
+        };
+        let next = match sent {
+            Signal::Interrupt if since.elapsed() >= self.limits.sigterm_delay => Signal::Terminate,
+            Signal::Terminate if since.elapsed() >= self.limits.sigterm_delay + self.limits.sigkill_delay => {
+                Signal::Kill
+            }
+            _ => return,
+        };
+        interrupt::signal(pid, next);
+        self.interrupted = Some((since, next));
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.is_some()
+    }
+
+    /// Whether the cell was killed because the session is being thrown away.
+    pub fn discarded(&self) -> bool {
+        self.interrupted.is_some() && self.interrupt.discarded()
+    }
+
+    /// The memory limit, if the process that ended with `status` ran out of
+    /// it: it failed, and `stderr` reports a failed allocation. A cell that
+    /// merely prints such a message and carries on is not stopped by it.
+    pub fn memory_exhausted(&self, status: ExitStatus, stderr: &str) -> Option<Limit> {
+        let mb = self.limits.memory_mb?;
+        if status.success() {
+            return None;
+        }
+        let lower = stderr.to_ascii_lowercase();
+        (lower.contains("out of memory") || lower.contains("cannot allocate")).then_some(Limit::Memory(mb))
+    }
+
+    /// Count `text` against the output cap.
+    pub fn output(&mut self, text: &str) -> Result<(), Limit> {
+        self.output += text.len();
+        match self.limits.output_bytes {
+            Some(cap) if self.output > cap => Err(Limit::Output(cap)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn expired(&self) -> Result<(), Limit> {
+        match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => Err(Limit::Time(timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long to wait for the next event: the flush interval, or less if
+    /// the deadline comes sooner.
+    pub fn wait(&self) -> Duration {
+        self.deadline
+            .map_or(FLUSH_INTERVAL, |d| d.saturating_duration_since(Instant::now()).min(FLUSH_INTERVAL))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{Limit, Limits, Watchdog};
+    use crate::{
+        config::Config,
+        interrupt::Interrupt,
+        testing::{self, TempDir},
+    };
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// The SIGTERM and SIGKILL delays of the tests. Only lower bounds on the
+    /// time to stop a stub are checked; which signal ended it shows the order.
+    const DELAY: f64 = 0.5;
+
+    /// Run `script` as a stand-in for Arturo, interrupt it (or discard it)
+    /// and escalate until it exits. Returns the signal that ended it and how
+    /// long that took.
+    fn stop(script: &str, discard: bool) -> (Option<i32>, Duration) {
+        let dir = TempDir::new();
+        let config = Config {
+            sigterm_delay_secs: Some(DELAY),
+            sigkill_delay_secs: Some(DELAY),
+            ..testing::stub(&dir, script)
+        };
+
+        let mut child = config.command().spawn().unwrap();
+        // Let the shell install its traps first.
+        thread::sleep(Duration::from_millis(200));
+        let interrupt = Arc::new(Interrupt::default());
+        let mut watchdog = Watchdog::start(&config.limits(), &interrupt);
+        if discard {
+            interrupt.discard();
+        } else {
+            interrupt.request();
+        }
+
+        let start = Instant::now();
+        let status = loop {
+            watchdog.escalate(child.id());
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            assert!(start.elapsed() < Duration::from_secs(30), "the stub was never stopped");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(watchdog.interrupted());
+        (status.signal(), start.elapsed())
+    }
+
+    const IGNORE_INT: &str = "trap '' INT\nwhile :; do sleep 0.05; done\n";
+    const IGNORE_INT_AND_TERM: &str = "trap '' INT TERM\nwhile :; do sleep 0.05; done\n";
+
+    #[test]
+    fn sigint_first() {
+        let (signal, _) = stop("while :; do sleep 0.05; done\n", false);
+        assert_eq!(signal, Some(libc::SIGINT));
+    }
+
+    #[test]
+    fn sigterm_after_its_delay() {
+        let (signal, elapsed) = stop(IGNORE_INT, false);
+        assert_eq!(signal, Some(libc::SIGTERM));
+        assert!(elapsed >= Duration::from_secs_f64(DELAY), "{elapsed:?}");
+    }
+
+    #[test]
+    fn sigkill_after_both_delays() {
+        let (signal, elapsed) = stop(IGNORE_INT_AND_TERM, false);
+        assert_eq!(signal, Some(libc::SIGKILL));
+        assert!(elapsed >= Duration::from_secs_f64(2.0 * DELAY), "{elapsed:?}");
+    }
+
+    #[test]
+    fn discard_kills_at_once() {
+        // Escalating to SIGKILL would take both delays.
+        let (signal, elapsed) = stop(IGNORE_INT_AND_TERM, true);
+        assert_eq!(signal, Some(libc::SIGKILL));
+        assert!(elapsed < Duration::from_secs_f64(2.0 * DELAY), "{elapsed:?}");
+    }
+
+    #[test]
+    fn memory_is_exhausted_only_when_the_process_fails() {
+        let limits = Limits { memory_mb: Some(64), ..Limits::default() };
+        let watchdog = Watchdog::start(&limits, &Arc::new(Interrupt::default()));
+        let failed = ExitStatus::from_raw(1 << 8);
+        let aborted = ExitStatus::from_raw(libc::SIGABRT);
+
+        assert_eq!(watchdog.memory_exhausted(failed, "out of memory\n"), Some(Limit::Memory(64)));
+        assert_eq!(watchdog.memory_exhausted(aborted, "Cannot allocate memory\n"), Some(Limit::Memory(64)));
+        assert_eq!(watchdog.memory_exhausted(ExitStatus::from_raw(0), "out of memory\n"), None);
+        assert_eq!(watchdog.memory_exhausted(failed, "index out of range\n"), None);
+
+        let unlimited = Watchdog::start(&Limits::default(), &Arc::new(Interrupt::default()));
+        assert_eq!(unlimited.memory_exhausted(failed, "out of memory\n"), None);
+    }
+}
+
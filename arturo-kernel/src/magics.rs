@@ -0,0 +1,114 @@
+//! `%` magics: session commands handled by the kernel itself.
+//!
+//! A magic is the first line of a cell. Anything after that line still runs
+//! as Arturo code, so `%time` can sit on top of the cell it times and `%load`
+//! can be followed by code that uses what the file defined.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Magic<'a> {
+    /// `%reset`: forget every definition and restart the worker.
+    Reset,
+    /// `%who`: list the names defined in the session.
+    Who,
+    /// `%preamble`: show the code replayed in front of cells in per-cell mode.
+    Preamble,
+    /// `%time [code]`: time the code on the magic line, or the rest of the cell.
+    Time(&'a str),
+    /// `%load file.art`: run a file as part of the cell.
+    Load(&'a str),
+    /// `%env`, `%env NAME`, `%env NAME=value`.
+    Env(Env<'a>),
+    Unknown(&'a str),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Env<'a> {
+    List,
+    Get(&'a str),
+    Set(&'a str, &'a str),
+    /// An `=` where neither form puts one, as in `%env A = 1`.
+    Invalid,
+}
+
+/// Names accepted by [`parse`], for help text.
+pub const NAMES: &str = "%reset, %who, %preamble, %time, %load, %env";
+
+/// The magic on the first line of `code`, if any, and the rest of the cell
+/// after that line.
+pub fn parse(code: &str) -> Option<(Magic<'_>, &str)> {
+    let (first, rest) = code.split_once('\n').unwrap_or((code, ""));
+    let line = first.trim().strip_prefix('%')?;
+    // `%` on its own is Arturo's `mod`: `% 10 3`.
+    if !line.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return None;
+    }
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+
+    let magic = match name {
+        "reset" => Magic::Reset,
+        "who" => Magic::Who,
+        "preamble" => Magic::Preamble,
+        "time" => Magic::Time(args),
+        "load" => Magic::Load(args),
+        "env" => Magic::Env(env(args)),
+        _ => Magic::Unknown(name),
+    };
+    Some((magic, rest))
+}
+
+/// `NAME=value` or `NAME value` sets, a lone `NAME` reads.
+fn env(args: &str) -> Env<'_> {
+    if args.is_empty() {
+        return Env::List;
+    }
+    let Some(end) = args.find(|c: char| c == '=' || c.is_whitespace()) else {
+        return Env::Get(args);
+    };
+    let (name, rest) = args.split_at(end);
+    let value = rest.strip_prefix('=').unwrap_or(rest).trim();
+    if value.starts_with('=') {
+        Env::Invalid
+    } else {
+        Env::Set(name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Env, Magic};
+
+    #[test]
+    fn plain_code_is_not_a_magic() {
+        assert_eq!(parse("print 1"), None);
+        assert_eq!(parse("x: 10 % 3"), None);
+        assert_eq!(parse("% 10 3"), None);
+        assert_eq!(parse("%\nprint 1"), None);
+    }
+
+    #[test]
+    fn magic_line_and_rest() {
+        assert_eq!(parse("%who"), Some((Magic::Who, "")));
+        assert_eq!(parse("%time\nloop 1..3 'i [print i]"), Some((Magic::Time(""), "loop 1..3 'i [print i]")));
+        assert_eq!(parse("%time fib 25"), Some((Magic::Time("fib 25"), "")));
+        assert_eq!(parse("%load lib/utils.art\nprint 1"), Some((Magic::Load("lib/utils.art"), "print 1")));
+        assert_eq!(parse("%frobnicate"), Some((Magic::Unknown("frobnicate"), "")));
+    }
+
+    #[test]
+    fn env_forms() {
+        assert_eq!(parse("%env"), Some((Magic::Env(Env::List), "")));
+        assert_eq!(parse("%env HOME"), Some((Magic::Env(Env::Get("HOME")), "")));
+        assert_eq!(parse("%env DEBUG=1"), Some((Magic::Env(Env::Set("DEBUG", "1")), "")));
+        assert_eq!(parse("%env GREETING hello there"), Some((Magic::Env(Env::Set("GREETING", "hello there")), "")));
+        assert_eq!(parse("%env GREETING=a=b"), Some((Magic::Env(Env::Set("GREETING", "a=b")), "")));
+        assert_eq!(parse("%env EMPTY="), Some((Magic::Env(Env::Set("EMPTY", "")), "")));
+    }
+
+    #[test]
+    fn env_rejects_a_stray_equals_sign() {
+        assert_eq!(parse("%env A = 1"), Some((Magic::Env(Env::Invalid), "")));
+        assert_eq!(parse("%env A =1"), Some((Magic::Env(Env::Invalid), "")));
+        assert_eq!(parse("%env A==1"), Some((Magic::Env(Env::Invalid), "")));
+    }
+}
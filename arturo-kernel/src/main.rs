@@ -1,34 +1,77 @@
 //! arturo-kernel — Jupyter kernel for the Arturo programming language
 //!
-//! Implements the Jupyter messaging protocol (v5.3) over ZeroMQ.
+//! Implements the Jupyter messaging protocol (v5.4) over ZeroMQ.
 //! Zed's REPL uses this kernel when you open the REPL panel on a .art file.
 //!
 //! Architecture:
 //!   - Shell socket:   receives execute_request, kernel_info_request, etc.
-//!   - IOPub socket:   broadcasts status, stream output, and errors to all clients
-//!   - Stdin socket:   (input_request — not used, kept for protocol compliance)
+//!   - IOPub socket:   broadcasts status, stream output, displays, results and errors
+//!   - Stdin socket:   input_request / input_reply for Arturo `input` calls
 //!   - Control socket: handles shutdown_request, interrupt_request
 //!   - Heartbeat:      echoes back raw bytes to signal liveness
 //!
 //! State persistence:
-//!   Arturo has no persistent session mode suitable for piping, so each cell
-//!   is executed via `arturo --no-color -e '<code>'`. To share state across
-//!   cells the kernel tracks variable assignments from prior cells and prepends
-//!   them to each new execution.
+//!   Cells run inside a long-lived Arturo worker process (see `worker.rs`), so
+//!   definitions and side effects happen exactly once. The kernel still tracks
+//!   variable assignments from prior cells: if the worker dies, it falls back
+//!   to executing each cell via `arturo --no-color -e '<code>'` with those
+//!   assignments prepended.
+
+mod builtins;
+mod comms;
+mod completion;
+mod config;
+mod connection;
+mod debugger;
+mod errors;
+mod history;
+mod inspection;
+mod interrupt;
+mod lexer;
+mod limits;
+mod magics;
+mod markers;
+mod output;
+mod preamble;
+mod results;
+mod signing;
+mod sourcemap;
+#[cfg(test)]
+mod testing;
+mod worker;
 
 use chrono::Utc;
-use hmac::{Hmac, Mac};
-use serde::Deserialize;
-use serde_json::{json, Value};
-use sha2::Sha256;
+use comms::{Action, Comms};
+use config::Config;
+use connection::ConnectionInfo;
+use debugger::Debugger;
+use errors::ArturoError;
+use history::History;
+use interrupt::{Interrupt, Signal};
+use lexer::Completeness;
+use limits::{Stopped, Watchdog};
+use magics::Magic;
+use markers::{MarkerScanner, Markers};
+use output::{CellIo, Event, Input, Stream, StreamBuffer};
+use preamble::Preamble;
+use signing::SeenIds;
+use sourcemap::SourceMap;
+use serde_json::{json, Map, Value};
 use std::{
+    collections::HashSet,
     env, fs,
     path::PathBuf,
-    process::{Command, Stdio},
-    sync::{Arc, Mutex},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
     thread,
+    time::Instant,
 };
 use uuid::Uuid;
+use worker::{Worker, WorkerDied};
 use zmq::{Context, Socket, SocketType};
 
 // ── Jupyter wire-protocol types ──────────────────────────────────────────────
@@ -60,12 +103,9 @@ impl JupyterMessage {
         let metadata_raw = &rest[3];
         let content_raw  = &rest[4];
 
-        if !key.is_empty() {
-            let expected = compute_hmac(key, &[header_raw, parent_raw, metadata_raw, content_raw]);
-            if expected != hmac_sig {
-                eprintln!("[arturo-kernel] HMAC mismatch — dropping message");
-                return None;
-            }
+        if !signing::verify(key, &[header_raw, parent_raw, metadata_raw, content_raw], hmac_sig) {
+            eprintln!("[arturo-kernel] HMAC mismatch — dropping message");
+            return None;
         }
 
         let buffers = rest[5..].to_vec();
@@ -86,7 +126,7 @@ impl JupyterMessage {
         let metadata_raw = serde_json::to_vec(&self.metadata).unwrap();
         let content_raw  = serde_json::to_vec(&self.content).unwrap();
 
-        let sig = compute_hmac(key, &[&header_raw, &parent_raw, &metadata_raw, &content_raw]);
+        let sig = signing::sign(key, &[&header_raw, &parent_raw, &metadata_raw, &content_raw]);
 
         let mut frames: Vec<Vec<u8>> = self.identities.clone();
         frames.push(b"<IDS|MSG>".to_vec());
@@ -102,17 +142,6 @@ impl JupyterMessage {
     }
 }
 
-fn compute_hmac(key: &[u8], parts: &[&[u8]]) -> String {
-    if key.is_empty() {
-        return String::new();
-    }
-    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
-    for part in parts {
-        mac.update(part);
-    }
-    hex::encode(mac.finalize().into_bytes())
-}
-
 fn make_header(msg_type: &str, session: &str) -> Value {
     json!({
         "msg_id":   Uuid::new_v4().to_string(),
@@ -120,7 +149,7 @@ fn make_header(msg_type: &str, session: &str) -> Value {
         "username": "arturo-kernel",
         "date":     Utc::now().to_rfc3339(),
         "msg_type": msg_type,
-        "version":  "5.3"
+        "version":  "5.4"
     })
 }
 
@@ -132,7 +161,9 @@ fn send_message(socket: &Socket, msg: &JupyterMessage, key: &[u8]) {
     }
 }
 
-fn recv_message(socket: &Socket, key: &[u8]) -> Option<JupyterMessage> {
+/// Receive one message, dropping it if its signature is wrong or, with
+/// `seen`, if its `msg_id` is missing or was received before.
+fn recv_message(socket: &Socket, key: &[u8], seen: Option<&Mutex<SeenIds>>) -> Option<JupyterMessage> {
     let mut frames = Vec::new();
     loop {
         let frame = socket.recv_bytes(0).ok()?;
@@ -141,204 +172,491 @@ fn recv_message(socket: &Socket, key: &[u8]) -> Option<JupyterMessage> {
             break;
         }
     }
-    JupyterMessage::from_frames(frames, key)
-}
-
-// ── Connection file ───────────────────────────────────────────────────────────
-
-#[derive(Debug, Deserialize)]
-struct ConnectionInfo {
-    ip: String,
-    transport: String,
-    shell_port: u16,
-    iopub_port: u16,
-    stdin_port: u16,
-    control_port: u16,
-    hb_port: u16,
-    key: String,
-    #[allow(dead_code)]
-    signature_scheme: String,
-    #[allow(dead_code)]
-    kernel_name: Option<String>,
-}
-
-impl ConnectionInfo {
-    fn endpoint(&self, port: u16) -> String {
-        format!("{}://{}:{}", self.transport, self.ip, port)
+    let msg = JupyterMessage::from_frames(frames, key)?;
+    if let Some(seen) = seen {
+        // Without an id a replay could not be told apart.
+        let Some(msg_id) = msg.header["msg_id"].as_str().filter(|id| !id.is_empty()) else {
+            eprintln!("[arturo-kernel] Message without a msg_id — dropping message");
+            return None;
+        };
+        if !seen.lock().unwrap().insert(msg_id) {
+            eprintln!("[arturo-kernel] Duplicate msg_id {msg_id} — dropping message");
+            return None;
+        }
     }
+    Some(msg)
 }
 
 // ── Session state ─────────────────────────────────────────────────────────────
 
 #[derive(Debug, Default)]
 struct KernelState {
-    /// Variable and function definitions from prior cells, prepended on each run.
-    preamble: Vec<String>,
+    /// Variable and function definitions from prior cells, prepended on each
+    /// run in per-cell mode.
+    preamble: Preamble,
     execution_count: u32,
     tmp_dir: PathBuf,
-    running_pid: Option<u32>,
+    /// Raised by `interrupt_request`, acted on by the running cell's watchdog.
+    interrupt: Arc<Interrupt>,
+    /// Comm registrations and open comms. Shared with the shell loop, which
+    /// opens, closes and lists comms while a cell runs.
+    comms: Arc<Mutex<Comms>>,
+    /// Persistent Arturo process; `None` once it has died (or never started),
+    /// in which case cells run one-shot with the preamble prepended.
+    worker: Option<Worker>,
+    /// How Arturo is started; `%env` adds to its environment.
+    config: Config,
 }
 
 impl KernelState {
-    fn new() -> Self {
+    fn new(config: Config) -> Self {
         let tmp_dir = env::temp_dir().join(format!("arturo-kernel-{}", Uuid::new_v4()));
         fs::create_dir_all(&tmp_dir).ok();
-        KernelState {
-            preamble: Vec::new(),
+        let mut state = KernelState {
+            preamble: Preamble::default(),
             execution_count: 0,
             tmp_dir,
-            running_pid: None,
-        }
+            interrupt: Arc::new(Interrupt::default()),
+            comms: Arc::new(Mutex::new(Comms::default())),
+            worker: None,
+            config,
+        };
+        state.start_worker();
+        state
     }
 
-    fn execute(&mut self, code: &str) -> (String, String, bool) {
-        self.execution_count += 1;
+    fn start_worker(&mut self) {
+        self.worker = match Worker::spawn(&self.tmp_dir, &self.config) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                eprintln!("[arturo-kernel] Could not start worker, using per-cell mode: {e}");
+                None
+            }
+        };
+    }
 
-        let mut full_code = self.preamble.join("\n");
-        if !full_code.is_empty() {
-            full_code.push('\n');
-        }
-        full_code.push_str(code);
+    /// Start the session over: a new worker, temp dir and execution count,
+    /// no definitions or comms, and `config` as given on the command line. Interrupt
+    /// requests, the discard that stopped the old session included, are dropped.
+    fn restart(&mut self, config: Config) {
+        self.worker = None;
+        let mut fresh = KernelState::new(config);
+        fresh.interrupt = Arc::clone(&self.interrupt);
+        fresh.comms = Arc::clone(&self.comms);
+        *self = fresh;
+        self.interrupt.reset();
+        *self.comms.lock().unwrap() = Comms::default();
+    }
 
-        let result = run_arturo(&full_code, self);
+    /// Run a cell, passing its output, `input` prompts and displays to `io`
+    /// while it runs. A `%` magic on the first line is handled here.
+    ///
+    /// Returns the cell's full stdout, and the parsed error if the cell failed.
+    fn execute(
+        &mut self,
+        code: &str,
+        io: &mut dyn CellIo,
+    ) -> (String, Option<ArturoError>) {
+        let Some((magic, rest)) = magics::parse(code) else {
+            return self.run(code, io);
+        };
+        // The magic line is blanked or replaced by the code it stands for; the
+        // rest of the cell keeps its line numbers (see `%load` for a file).
+        let cell = |first: &str| format!("{first}\n{rest}");
+
+        match magic {
+            Magic::Time(inline) => {
+                let start = Instant::now();
+                let outcome = self.run(&cell(inline), io);
+                io.output(Stream::Stdout, &format!("Wall time: {:.2?}\n", start.elapsed()));
+                return outcome;
+            }
+            Magic::Load("") => return self.usage_error("%load needs a file name"),
+            Magic::Load(path) => {
+                let source = match fs::read_to_string(self.config.resolve(path)) {
+                    Ok(source) => source,
+                    Err(e) => return self.usage_error(format!("could not read {path}: {e}")),
+                };
+                let source = source.trim_end_matches('\n');
+                let mut map = SourceMap::default();
+                map.push_loaded(self.execution_count + 1, 1, source);
+                map.push(self.execution_count + 1, 2, rest);
+                return self.run_mapped(&cell(source), map, io);
+            }
+            Magic::Reset => {
+                self.preamble = Preamble::default();
+                *self.comms.lock().unwrap() = Comms::default();
+                self.worker = None;
+                self.start_worker();
+            }
+            Magic::Who => {
+                // A name repeats while an older definition is still needed.
+                let mut seen = HashSet::new();
+                let names: Vec<&str> = self.preamble.names().filter(|name| seen.insert(*name)).collect();
+                let listing = if names.is_empty() {
+                    "No names defined in this session.\n".to_string()
+                } else {
+                    names.join("\t") + "\n"
+                };
+                io.output(Stream::Stdout, &listing);
+            }
+            Magic::Preamble => {
+                let preamble = self.preamble.render();
+                let listing = if preamble.is_empty() { "The preamble is empty.".to_string() } else { preamble };
+                io.output(Stream::Stdout, &(listing + "\n"));
+            }
+            Magic::Env(magics::Env::List) => {
+                let listing: String = self.config.env.iter().map(|(name, value)| format!("{name}={value}\n")).collect();
+                let listing = if listing.is_empty() { "No variables set with %env.\n".to_string() } else { listing };
+                io.output(Stream::Stdout, &listing);
+            }
+            Magic::Env(magics::Env::Get(name)) => {
+                match self.config.env.get(name).cloned().or_else(|| env::var(name).ok()) {
+                    Some(value) => io.output(Stream::Stdout, &format!("{value}\n")),
+                    None => return self.usage_error(format!("environment variable {name} is not set")),
+                }
+            }
+            Magic::Env(magics::Env::Invalid) => {
+                return self.usage_error("%env takes NAME=value or NAME value");
+            }
+            Magic::Env(magics::Env::Set(name, value)) => {
+                if name.is_empty() {
+                    return self.usage_error(format!("invalid environment variable name `{name}`"));
+                }
+                self.config.env.insert(name.to_string(), value.to_string());
+                io.output(Stream::Stdout, &format!("env: {name}={value}\n"));
+                if self.worker.is_some() {
+                    io.output(
+                        Stream::Stderr,
+                        "The running Arturo worker keeps its environment; %reset restarts it with the new one.\n",
+                    );
+                }
+            }
+            Magic::Unknown(name) => {
+                return self.usage_error(format!("unknown magic %{name}; available: {}", magics::NAMES));
+            }
+        }
 
-        if !result.2 {
-            self.preamble.extend(extract_assignments(code));
+        if rest.trim().is_empty() {
+            self.execution_count += 1;
+            (String::new(), None)
+        } else {
+            self.run(&cell(""), io)
         }
+    }
 
-        result
+    fn usage_error(&mut self, message: impl Into<String>) -> (String, Option<ArturoError>) {
+        self.execution_count += 1;
+        (String::new(), Some(ArturoError::usage(message)))
     }
-}
 
-impl Drop for KernelState {
-    fn drop(&mut self) {
-        fs::remove_dir_all(&self.tmp_dir).ok();
+    /// Run Arturo code as cell `execution_count + 1`.
+    fn run(&mut self, code: &str, io: &mut dyn CellIo) -> (String, Option<ArturoError>) {
+        let map = SourceMap::cell(self.execution_count + 1, code);
+        self.run_mapped(code, map, io)
     }
-}
 
-// ── Arturo utilities ──────────────────────────────────────────────────────────
+    /// Run Arturo code as cell `execution_count + 1`, where `map` places the
+    /// lines of `code` in the cell.
+    fn run_mapped(&mut self, code: &str, map: SourceMap, io: &mut dyn CellIo) -> (String, Option<ArturoError>) {
+        self.execution_count += 1;
 
-fn extract_assignments(code: &str) -> Vec<String> {
-    let mut result = Vec::new();
-    let mut depth = 0i32;
-    let mut current_block: Vec<&str> = Vec::new();
-    let mut in_block = false;
+        let cell = self.execution_count;
+        // What actually runs captures the last expression's value.
+        let runnable = results::capture_last(code).unwrap_or_else(|| code.to_string());
+        let mut watchdog = self.watchdog();
+        let (program, outcome) = self.run_code(code, &runnable, &map, io, &mut watchdog);
+
+        // A cell that was interrupted or stopped at a limit is not recorded,
+        // and is not blamed on the preamble: the session's definitions stay
+        // as they were.
+        match outcome {
+            Ok((stdout, _, false)) => {
+                self.preamble.record(code, &map);
+                (stdout, None)
+            }
+            _ if watchdog.interrupted() => {
+                let stdout = outcome.map_or_else(|stopped| stopped.stdout, |(stdout, _, _)| stdout);
+                (stdout, Some(ArturoError::kernel("KeyboardInterrupt", "Execution interrupted")))
+            }
+            Err(stopped) => (stopped.stdout, Some(stopped.limit.error())),
+            Ok((stdout, stderr, true)) => {
+                let mut error = ArturoError::from_output(&stderr, &stdout, &program.source, &program.map);
+                // A replayed definition from an earlier cell is broken: drop it so
+                // it stops failing every later cell.
+                if let Some(location) = error.location.filter(|l| l.cell != cell) {
+                    if let Some(name) = self.preamble.discard(location) {
+                        error.traceback.push(format!(
+                            "  `{name}` from In [{}] was removed from the session preamble",
+                            location.cell
+                        ));
+                    }
+                }
+                (stdout, Some(error))
+            }
+        }
+    }
+
+    fn watchdog(&self) -> Watchdog {
+        Watchdog::start(&self.config.limits(), &self.interrupt)
+    }
 
-    for line in code.lines() {
-        let trimmed = line.trim();
+    /// Run `runnable`, which is `code` (placed in its cell by `map`) with the
+    /// same lines, in the worker, or after the preamble in a fresh process
+    /// once the worker is gone.
+    ///
+    /// Returns the program that ran, and `(stdout, stderr, is_error)` or what
+    /// was captured before a limit stopped it.
+    fn run_code(
+        &mut self,
+        code: &str,
+        runnable: &str,
+        map: &SourceMap,
+        io: &mut dyn CellIo,
+        watchdog: &mut Watchdog,
+    ) -> (Program, Result<(String, String, bool), Stopped>) {
+        let Some(worker) = self.worker.as_mut() else {
+            let markers = Markers::new();
+            let (source, map) = self.preamble.program(code, map, &markers.prelude());
+            let program = format!("{}{runnable}", &source[..source.len() - code.len()]);
+            let outcome = run_arturo(&program, &markers, self, io, watchdog);
+            return (Program { source, map }, outcome);
+        };
 
-        for ch in line.chars() {
-            match ch {
-                '[' | '{' | '(' => depth += 1,
-                ']' | '}' | ')' => depth -= 1,
-                _ => {}
+        let program = Program { source: code.to_string(), map: map.clone() };
+        let outcome = match worker.run_cell(runnable, io, watchdog) {
+            Ok(result) => Ok(result),
+            // The session is going away: a new worker would only be thrown away with it.
+            Err(WorkerDied { stdout, stderr, .. }) if watchdog.discarded() => {
+                self.worker = None;
+                Ok((stdout, stderr, true))
             }
-        }
+            // The kernel stopped the worker: start over with the same definitions.
+            Err(WorkerDied { stdout, stderr, limit }) if limit.is_some() || watchdog.interrupted() => {
+                self.restore_worker(io);
+                match limit {
+                    Some(limit) => Err(Stopped { stdout, limit }),
+                    None => Ok((stdout, stderr, true)),
+                }
+            }
+            Err(died) => {
+                eprintln!("[arturo-kernel] Worker exited, falling back to per-cell mode");
+                self.worker = None;
+                let notice = "Arturo worker exited; later cells will re-run the accumulated preamble.\n";
+                io.output(Stream::Stderr, notice);
+                Ok((died.stdout, died.stderr + notice, true))
+            }
+        };
+        (program, outcome)
+    }
 
-        if in_block {
-            current_block.push(line);
-            if depth == 0 {
-                result.push(current_block.join("\n"));
-                current_block.clear();
-                in_block = false;
+    /// Replace a worker that the kernel stopped with a fresh one that has
+    /// replayed the preamble, so the session's definitions survive.
+    ///
+    /// Arturo cannot catch the interrupt inside a cell, so the whole worker
+    /// goes. Replaying runs each definition again, side effects included,
+    /// which `io` is told about.
+    fn restore_worker(&mut self, io: &mut dyn CellIo) {
+        self.worker = None;
+        self.start_worker();
+        let mut watchdog = self.watchdog();
+        let Some(worker) = self.worker.as_mut() else {
+            return;
+        };
+        let (program, _) = self.preamble.program("", &SourceMap::default(), "");
+        if program.trim().is_empty() {
+            return;
+        }
+        let replayed = "The Arturo worker was restarted and the session's definitions were run again in it, \
+                        repeating any side effects they have (output, files written, random values)";
+        match worker.run_cell(&program, &mut Capture::default(), &mut watchdog) {
+            Ok((_, _, false)) => io.output(Stream::Stderr, &format!("{replayed}.\n")),
+            Ok(_) => io.output(Stream::Stderr, &format!("{replayed}; some of them failed.\n")),
+            Err(_) => {
+                eprintln!("[arturo-kernel] Replaying the preamble failed, falling back to per-cell mode");
+                self.worker = None;
             }
-            continue;
         }
+    }
 
-        if trimmed.is_empty()
-            || trimmed.starts_with(';')
-            || trimmed.starts_with("print ")
-            || trimmed.starts_with("echo ")
-            || trimmed.starts_with("prints ")
-            || trimmed.starts_with("inspect ")
-        {
-            continue;
-        }
+    /// Run a comm handler call in the session. It is not a cell: nothing is
+    /// recorded, and a failure only shows on stderr.
+    fn run_handler(&mut self, code: &str, io: &mut dyn CellIo) {
+        let mut watchdog = self.watchdog();
+        let map = SourceMap::cell(self.execution_count, code);
+        self.run_code(code, code, &map, io, &mut watchdog).1.ok();
+    }
 
-        if let Some(colon_pos) = trimmed.find(':') {
-            let before = &trimmed[..colon_pos];
-            let after  = trimmed[colon_pos + 1..].trim();
-            let is_ident = !before.is_empty()
-                && before.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '?')
-                && !after.is_empty();
-
-            if is_ident {
-                if depth > 0 {
-                    current_block.push(line);
-                    in_block = true;
-                } else {
-                    result.push(line.to_string());
-                }
+    /// Evaluate the `user_expressions` of an `execute_request` in the session,
+    /// publishing nothing, and build the reply's map of MIME bundles or errors.
+    fn user_expressions(&mut self, expressions: &Map<String, Value>) -> Value {
+        let results: Map<String, Value> = expressions
+            .iter()
+            .map(|(name, expression)| (name.clone(), self.evaluate(expression.as_str().unwrap_or(""))))
+            .collect();
+        Value::Object(results)
+    }
+
+    fn evaluate(&mut self, expression: &str) -> Value {
+        // The newline keeps a trailing comment from swallowing the `]`.
+        let runnable = format!("arturoKernelResult @[{expression}\n]");
+        let mut capture = Capture::default();
+        let mut watchdog = self.watchdog();
+        let (program, outcome) =
+            self.run_code(&runnable, &runnable, &SourceMap::default(), &mut capture, &mut watchdog);
+
+        let error = match outcome {
+            Ok((_, _, false)) => None,
+            _ if watchdog.interrupted() => Some(ArturoError::kernel("KeyboardInterrupt", "Execution interrupted")),
+            Ok((stdout, stderr, true)) => {
+                Some(ArturoError::from_output(&stderr, &stdout, &program.source, &program.map))
             }
+            Err(stopped) => Some(stopped.limit.error()),
+        };
+        if let Some(error) = error {
+            return json!({
+                "status": "error",
+                "ename": error.ename,
+                "evalue": error.evalue,
+                "traceback": error.traceback,
+            });
         }
+        json!({
+            "status": "ok",
+            "data": { "text/plain": capture.result.unwrap_or_else(|| "null".to_string()) },
+            "metadata": {},
+        })
     }
+}
 
-    result
+/// The program `run_code` ran, with the cell as written in place of what
+/// actually ran (line for line the same), and where its lines came from.
+struct Program {
+    source: String,
+    map: SourceMap,
 }
 
-fn run_arturo(code: &str, state: &mut KernelState) -> (String, String, bool) {
-    let mut cmd = Command::new("arturo");
+impl Drop for KernelState {
+    fn drop(&mut self) {
+        self.worker = None;
+        fs::remove_dir_all(&self.tmp_dir).ok();
+    }
+}
+
+// ── Arturo utilities ──────────────────────────────────────────────────────────
+
+/// Run `code` as a one-shot `arturo -e` program. `code` must start with
+/// `markers.prelude()` for `input` calls and results to reach `io`.
+fn run_arturo(
+    code: &str,
+    markers: &Markers,
+    state: &mut KernelState,
+    io: &mut dyn CellIo,
+    watchdog: &mut Watchdog,
+) -> Result<(String, String, bool), Stopped> {
+    let mut cmd = state.config.command();
     cmd.arg("--no-color")
         .arg("-e")
         .arg(code)
+        .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
-    let child = match cmd.spawn() {
+    let mut child = match cmd.spawn() {
         Ok(c) => c,
         Err(e) => {
-            return (
-                String::new(),
-                format!("Could not start `arturo`. Is Arturo installed and in PATH?\nError: {e}"),
-                true,
+            let message = format!(
+                "Could not start `{}`. Is Arturo installed and in PATH?\nError: {e}",
+                state.config.executable
             );
+            io.output(Stream::Stderr, &message);
+            return Ok((String::new(), message, true));
         }
     };
 
-    state.running_pid = Some(child.id());
+    let mut child_stdin = child.stdin.take().expect("stdin is piped");
+    let (tx, events) = mpsc::channel();
+    output::forward(child.stdout.take().expect("stdout is piped"), Stream::Stdout, tx.clone());
+    output::forward(child.stderr.take().expect("stderr is piped"), Stream::Stderr, tx);
 
-    let output = match child.wait_with_output() {
-        Ok(o) => o,
-        Err(e) => {
-            state.running_pid = None;
-            return (String::new(), format!("Failed to wait on `arturo -e`: {e}"), true);
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut exceeded = None;
+    {
+        let mut out = StreamBuffer::new(io);
+        let mut scanner = MarkerScanner::new(markers);
+        let mut open_pipes = 2;
+        while open_pipes > 0 {
+            watchdog.escalate(child.id());
+            if let Err(limit) = watchdog.expired() {
+                exceeded = Some(limit);
+                break;
+            }
+            let event = events.recv_timeout(watchdog.wait());
+            if let Ok(Event::Output(_, text)) = &event {
+                if let Err(limit) = watchdog.output(text) {
+                    exceeded = Some(limit);
+                    break;
+                }
+            }
+            match event {
+                Ok(Event::Output(Stream::Stdout, text)) => {
+                    for piece in scanner.feed(&text) {
+                        markers::handle(piece, &mut stdout, &mut child_stdin, &mut out);
+                    }
+                }
+                Ok(Event::Output(Stream::Stderr, text)) => {
+                    stderr.push_str(&text);
+                    out.push(Stream::Stderr, &text);
+                }
+                Ok(Event::Closed) => open_pipes -= 1,
+                Err(RecvTimeoutError::Timeout) => out.tick(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         }
-    };
-
-    state.running_pid = None;
-
-    let stdout  = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr  = String::from_utf8_lossy(&output.stderr).to_string();
-    let is_error = !output.status.success();
-
-    (stdout, stderr, is_error)
-}
+        if exceeded.is_none() {
+            let rest = scanner.finish();
+            stdout.push_str(&rest);
+            out.push(Stream::Stdout, &rest);
+        }
+    }
 
-fn interrupt_process(pid: u32) {
-    #[cfg(unix)]
-    unsafe {
-        libc::kill(pid as libc::pid_t, libc::SIGINT);
+    drop(child_stdin);
+    if exceeded.is_some() {
+        interrupt::signal(child.id(), Signal::Kill);
     }
-    #[cfg(windows)]
-    unsafe {
-        use windows_sys::Win32::Foundation::CloseHandle;
-        use windows_sys::Win32::System::Threading::{
-            OpenProcess, TerminateProcess, PROCESS_TERMINATE,
-        };
-        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
-        if handle != 0 {
-            TerminateProcess(handle, 1);
-            CloseHandle(handle);
+    let status = child.wait();
+    if let Some(limit) = exceeded {
+        return Err(Stopped { stdout, limit });
+    }
+
+    let limit = status.as_ref().ok().and_then(|&status| watchdog.memory_exhausted(status, &stderr));
+    let is_error = match status {
+        Ok(status) => !status.success(),
+        Err(e) => {
+            let message = format!("Failed to wait on `arturo -e`: {e}");
+            io.output(Stream::Stderr, &message);
+            stderr.push_str(&message);
+            true
         }
+    };
+
+    match limit {
+        Some(limit) => Err(Stopped { stdout, limit }),
+        None => Ok((stdout, stderr, is_error)),
     }
 }
 
 fn kernel_info_content() -> Value {
     json!({
         "status": "ok",
-        "protocol_version": "5.3",
+        "protocol_version": "5.4",
         "implementation": "arturo-kernel",
         "implementation_version": "0.1.0",
+        // The debug_request handshake is answered, but cells cannot be
+        // stepped, so the debugger is not advertised yet.
+        "debugger": false,
+        "supported_features": [],
         "language_info": {
             "name": "arturo",
             "version": "0.9",
@@ -374,64 +692,302 @@ fn publish_status(
     send_message(&iopub.lock().unwrap(), &msg, key);
 }
 
+fn publish_stream(
+    iopub: &Arc<Mutex<Socket>>,
+    key: &[u8],
+    session_id: &str,
+    parent: &JupyterMessage,
+    stream: Stream,
+    text: &str,
+) {
+    let msg = JupyterMessage {
+        identities: vec![],
+        header: make_header("stream", session_id),
+        parent_header: parent.header.clone(),
+        metadata: json!({}),
+        content: json!({ "name": stream.name(), "text": text }),
+        buffers: vec![],
+    };
+    send_message(&iopub.lock().unwrap(), &msg, key);
+}
+
+fn publish_display(
+    iopub: &Arc<Mutex<Socket>>,
+    key: &[u8],
+    session_id: &str,
+    parent: &JupyterMessage,
+    data: Value,
+    metadata: Value,
+) {
+    let msg = JupyterMessage {
+        identities: vec![],
+        header: make_header("display_data", session_id),
+        parent_header: parent.header.clone(),
+        metadata: json!({}),
+        content: json!({ "data": data, "metadata": metadata, "transient": {} }),
+        buffers: vec![],
+    };
+    send_message(&iopub.lock().unwrap(), &msg, key);
+}
+
+fn publish_comm(
+    iopub: &Arc<Mutex<Socket>>,
+    key: &[u8],
+    session_id: &str,
+    parent: &JupyterMessage,
+    msg_type: &str,
+    content: Value,
+    metadata: Value,
+) {
+    let msg = JupyterMessage {
+        identities: vec![],
+        header: make_header(msg_type, session_id),
+        parent_header: parent.header.clone(),
+        metadata,
+        content,
+        buffers: vec![],
+    };
+    send_message(&iopub.lock().unwrap(), &msg, key);
+}
+
+// ── Stdin helpers ─────────────────────────────────────────────────────────────
+
+/// Ask the front-end that sent `parent` for a line of input over the stdin
+/// channel and wait for its `input_reply`, or until an interrupt or shutdown
+/// is requested.
+fn request_input(
+    stdin: &Socket,
+    key: &[u8],
+    session_id: &str,
+    parent: &JupyterMessage,
+    prompt: &str,
+    interrupt: &Interrupt,
+    shutdown: &AtomicBool,
+) -> Input {
+    let request = JupyterMessage {
+        identities: parent.identities.clone(),
+        header: make_header("input_request", session_id),
+        parent_header: parent.header.clone(),
+        metadata: json!({}),
+        content: json!({ "prompt": prompt, "password": false }),
+        buffers: vec![],
+    };
+    send_message(stdin, &request, key);
+
+    loop {
+        if interrupt.pending() || shutdown.load(Ordering::SeqCst) {
+            return Input::Interrupted;
+        }
+        if stdin.poll(zmq::POLLIN, SHUTDOWN_POLL_MS.into()).map_or(true, |ready| ready == 0) {
+            continue;
+        }
+        let Some(reply) = recv_message(stdin, key, None) else {
+            continue;
+        };
+        // A reply to a prompt given up on earlier is not this prompt's answer.
+        if reply.header["msg_type"] == "input_reply" && reply.parent_header["msg_id"] == request.header["msg_id"] {
+            return Input::Line(reply.content["value"].as_str().unwrap_or("").to_string());
+        }
+        eprintln!("[arturo-kernel] Unexpected stdin msg: {}", reply.header["msg_type"]);
+    }
+}
+
+/// Routes a running cell's output, displays and input prompts to the
+/// front-end that sent the `execute_request`.
+struct ShellIo<'a> {
+    iopub: &'a Arc<Mutex<Socket>>,
+    comms: &'a Mutex<Comms>,
+    stdin: &'a Socket,
+    interrupt: &'a Interrupt,
+    shutdown: &'a AtomicBool,
+    key: &'a [u8],
+    session_id: &'a str,
+    parent: &'a JupyterMessage,
+    execution_count: u32,
+    silent: bool,
+    allow_stdin: bool,
+}
+
+impl CellIo for ShellIo<'_> {
+    fn output(&mut self, stream: Stream, text: &str) {
+        if !self.silent {
+            publish_stream(self.iopub, self.key, self.session_id, self.parent, stream, text);
+        }
+    }
+
+    fn input(&mut self, prompt: &str) -> Input {
+        if !self.allow_stdin {
+            return Input::Refused;
+        }
+        request_input(self.stdin, self.key, self.session_id, self.parent, prompt, self.interrupt, self.shutdown)
+    }
+
+    fn display(&mut self, data: Value, metadata: Value) {
+        if !self.silent {
+            publish_display(self.iopub, self.key, self.session_id, self.parent, data, metadata);
+        }
+    }
+
+    fn result(&mut self, text: &str) {
+        if !self.silent {
+            let msg = JupyterMessage {
+                identities: vec![],
+                header: make_header("execute_result", self.session_id),
+                parent_header: self.parent.header.clone(),
+                metadata: json!({}),
+                content: json!({
+                    "execution_count": self.execution_count,
+                    "data": { "text/plain": text },
+                    "metadata": {},
+                }),
+                buffers: vec![],
+            };
+            send_message(&self.iopub.lock().unwrap(), &msg, self.key);
+        }
+    }
+
+    // Comm traffic is published even for silent requests: it is not output.
+    fn comm(&mut self, action: Action) {
+        let mut comms = self.comms.lock().unwrap();
+        let (msg_type, content, metadata) = match action {
+            Action::Register { target_name, handler } => {
+                comms.register(&target_name, &handler);
+                return;
+            }
+            Action::Open { comm_id, target_name, data, metadata } => {
+                comms.opened(&comm_id, &target_name);
+                let content = json!({ "comm_id": comm_id, "target_name": target_name, "data": data });
+                ("comm_open", content, Value::Object(metadata))
+            }
+            Action::Msg { comm_id, data } => ("comm_msg", json!({ "comm_id": comm_id, "data": data }), json!({})),
+            Action::Close { comm_id, data } => {
+                comms.closed(&comm_id);
+                ("comm_close", json!({ "comm_id": comm_id, "data": data }), json!({}))
+            }
+        };
+        publish_comm(self.iopub, self.key, self.session_id, self.parent, msg_type, content, metadata);
+    }
+}
+
+/// Collects the value of a `user_expressions` entry; output is dropped.
+#[derive(Default)]
+struct Capture {
+    result: Option<String>,
+}
+
+impl CellIo for Capture {
+    fn output(&mut self, _stream: Stream, _text: &str) {}
+
+    fn input(&mut self, _prompt: &str) -> Input {
+        Input::Refused
+    }
+
+    fn display(&mut self, _data: Value, _metadata: Value) {}
+
+    fn result(&mut self, text: &str) {
+        self.result = Some(text.to_string());
+    }
+
+    fn comm(&mut self, _action: Action) {}
+}
+
 // ── Main ──────────────────────────────────────────────────────────────────────
 
+/// How often the socket loops check for shutdown while idle.
+const SHUTDOWN_POLL_MS: i32 = 100;
+
+/// How long closing a socket may wait to deliver what was sent on it, such as
+/// the `shutdown_reply`.
+const SHUTDOWN_LINGER_MS: i32 = 1000;
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: arturo-kernel <connection-file>");
-        std::process::exit(1);
-    }
+    let (connection_file, config) = match Config::from_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{message}");
+            std::process::exit(1);
+        }
+    };
 
-    let conn_json = fs::read_to_string(&args[1]).expect("Could not read connection file");
-    let conn: ConnectionInfo =
-        serde_json::from_str(&conn_json).expect("Invalid connection file JSON");
+    let mut conn = match ConnectionInfo::load(&connection_file) {
+        Ok(conn) => conn,
+        Err(message) => {
+            eprintln!("[arturo-kernel] {message}");
+            std::process::exit(1);
+        }
+    };
 
     let key = conn.key.as_bytes().to_vec();
+    // Shared by the shell and control channels, so a request replayed on
+    // either is refused.
+    let seen = config.reject_duplicate_ids.then(|| Arc::new(Mutex::new(SeenIds::default())));
     let session_id = Uuid::new_v4().to_string();
 
     eprintln!("[arturo-kernel] Starting. Session: {session_id}");
 
     let ctx = Context::new();
 
-    let shell = ctx.socket(SocketType::ROUTER).unwrap();
-    shell.bind(&conn.endpoint(conn.shell_port)).unwrap();
-
-    let iopub = ctx.socket(SocketType::PUB).unwrap();
-    iopub.bind(&conn.endpoint(conn.iopub_port)).unwrap();
-
-    let stdin = ctx.socket(SocketType::ROUTER).unwrap();
-    stdin.bind(&conn.endpoint(conn.stdin_port)).unwrap();
-
-    let control = ctx.socket(SocketType::ROUTER).unwrap();
-    control.bind(&conn.endpoint(conn.control_port)).unwrap();
-
-    let heartbeat = ctx.socket(SocketType::REP).unwrap();
-    heartbeat.bind(&conn.endpoint(conn.hb_port)).unwrap();
+    let [shell, iopub, stdin, control, heartbeat] = match conn.bind_all(&ctx, &connection_file) {
+        Ok(sockets) => sockets,
+        Err(message) => {
+            eprintln!("[arturo-kernel] {message}");
+            std::process::exit(1);
+        }
+    };
 
     eprintln!("[arturo-kernel] All sockets bound.");
 
+    for socket in [&control, &heartbeat] {
+        socket.set_rcvtimeo(SHUTDOWN_POLL_MS).unwrap();
+    }
+    for socket in [&shell, &iopub, &stdin, &control, &heartbeat] {
+        socket.set_linger(SHUTDOWN_LINGER_MS).unwrap();
+    }
+
     let iopub   = Arc::new(Mutex::new(iopub));
-    let state   = Arc::new(Mutex::new(KernelState::new()));
+    let state   = Arc::new(Mutex::new(KernelState::new(config.clone())));
+    let history = Arc::new(Mutex::new(History::open(&session_id, config.history_output)));
+    let comms   = Arc::clone(&state.lock().unwrap().comms);
+
+    // Set by shutdown_request; every loop below checks it between receives.
+    let shutdown = Arc::new(AtomicBool::new(false));
 
     // Heartbeat thread — echo raw bytes back
-    thread::spawn(move || loop {
-        if let Ok(msg) = heartbeat.recv_bytes(0) {
-            heartbeat.send(&msg, 0).ok();
-        }
-    });
+    let heartbeat_thread = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || while !shutdown.load(Ordering::SeqCst) {
+            if let Ok(msg) = heartbeat.recv_bytes(0) {
+                heartbeat.send(&msg, 0).ok();
+            }
+        })
+    };
 
     // Control thread — shutdown and interrupt
-    {
+    let control_thread = {
         let key       = key.clone();
+        let seen      = seen.clone();
         let session_id = session_id.clone();
+        let iopub     = Arc::clone(&iopub);
         let state     = Arc::clone(&state);
-        thread::spawn(move || loop {
-            if let Some(msg) = recv_message(&control, &key) {
+        let history   = Arc::clone(&history);
+        let shutdown  = Arc::clone(&shutdown);
+        // Not through `state`: the executor holds it while a cell runs.
+        let interrupt = Arc::clone(&state.lock().unwrap().interrupt);
+        let mut debugger = Debugger::default();
+        thread::spawn(move || while !shutdown.load(Ordering::SeqCst) {
+            if let Some(msg) = recv_message(&control, &key, seen.as_deref()) {
                 let msg_type = msg.header["msg_type"].as_str().unwrap_or("").to_string();
                 match msg_type.as_str() {
                     "shutdown_request" => {
                         let restart = msg.content["restart"].as_bool().unwrap_or(false);
+                        if restart {
+                            publish_status(&iopub, &key, &session_id, &msg, "starting");
+                            // Stop a running cell now, so the executor lets go of the
+                            // state without first restoring a worker for it.
+                            interrupt.discard();
+                            state.lock().unwrap().restart(config.clone());
+                            history.lock().unwrap().new_session();
+                        }
                         let reply = JupyterMessage {
                             identities: msg.identities.clone(),
                             header: make_header("shutdown_reply", &session_id),
@@ -442,15 +998,18 @@ fn main() {
                         };
                         send_message(&control, &reply, &key);
                         eprintln!("[arturo-kernel] Shutdown. restart={restart}");
-                        if !restart {
-                            std::process::exit(0);
+                        if restart {
+                            publish_status(&iopub, &key, &session_id, &msg, "idle");
+                        } else {
+                            // The shell loop winds down and drops the state,
+                            // which removes the worker and temp dir.
+                            interrupt.discard();
+                            shutdown.store(true, Ordering::SeqCst);
                         }
                     }
                     "interrupt_request" => {
-                        if let Some(pid) = state.lock().unwrap().running_pid {
-                            interrupt_process(pid);
-                            eprintln!("[arturo-kernel] Interrupted pid={pid}");
-                        }
+                        interrupt.request();
+                        eprintln!("[arturo-kernel] Interrupt requested");
                         let reply = JupyterMessage {
                             identities: msg.identities.clone(),
                             header: make_header("interrupt_reply", &session_id),
@@ -461,176 +1020,372 @@ fn main() {
                         };
                         send_message(&control, &reply, &key);
                     }
+                    "debug_request" => {
+                        publish_status(&iopub, &key, &session_id, &msg, "busy");
+                        let (response, events) = debugger.handle(&msg.content);
+                        let reply = JupyterMessage {
+                            identities: msg.identities.clone(),
+                            header: make_header("debug_reply", &session_id),
+                            parent_header: msg.header.clone(),
+                            metadata: json!({}),
+                            content: response,
+                            buffers: vec![],
+                        };
+                        send_message(&control, &reply, &key);
+                        for event in events {
+                            let event_msg = JupyterMessage {
+                                identities: vec![],
+                                header: make_header("debug_event", &session_id),
+                                parent_header: msg.header.clone(),
+                                metadata: json!({}),
+                                content: event,
+                                buffers: vec![],
+                            };
+                            send_message(&iopub.lock().unwrap(), &event_msg, &key);
+                        }
+                        publish_status(&iopub, &key, &session_id, &msg, "idle");
+                    }
                     other => eprintln!("[arturo-kernel] Unhandled control msg: {other}"),
                 }
             }
-        });
-    }
+        })
+    };
 
-    // Shell loop — main thread
-    loop {
-        let msg = match recv_message(&shell, &key) {
-            Some(m) => m,
-            None    => continue,
-        };
+    // Executor thread — runs the shell requests that need the session, one
+    // at a time. Only one thread may use the shell socket, so replies go back
+    // to the shell loop over an inproc pair.
+    //
+    // A comm message comes with the handler call the shell loop dispatched it
+    // to: the call runs in the session, so it waits behind a running cell.
+    let (jobs, queued) = mpsc::channel::<(JupyterMessage, Option<String>)>();
+    let executor = ctx.socket(SocketType::PAIR).unwrap();
+    executor.bind("inproc://shell-replies").unwrap();
+    let executor_thread = {
+        let replies   = ctx.socket(SocketType::PAIR).unwrap();
+        replies.connect("inproc://shell-replies").unwrap();
+        let key       = key.clone();
+        let session_id = session_id.clone();
+        let iopub     = Arc::clone(&iopub);
+        let state     = Arc::clone(&state);
+        let history   = Arc::clone(&history);
+        let comms     = Arc::clone(&comms);
+        let shutdown  = Arc::clone(&shutdown);
+        // Checked while a cell waits for input, which the watchdog cannot see.
+        let interrupt = Arc::clone(&state.lock().unwrap().interrupt);
+        thread::spawn(move || for (msg, call) in queued {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let msg_type = msg.header["msg_type"].as_str().unwrap_or("").to_string();
+            publish_status(&iopub, &key, &session_id, &msg, "busy");
 
-        let msg_type = msg.header["msg_type"].as_str().unwrap_or("").to_string();
-        eprintln!("[arturo-kernel] shell <- {msg_type}");
+            match msg_type.as_str() {
+                "execute_request" => {
+                    let code   = msg.content["code"].as_str().unwrap_or("").to_string();
+                    let silent = msg.content["silent"].as_bool().unwrap_or(false);
+                    let store_history = !silent && msg.content["store_history"].as_bool().unwrap_or(true);
 
-        match msg_type.as_str() {
-            "kernel_info_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("kernel_info_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: kernel_info_content(),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
-            }
+                    let exec_count = state.lock().unwrap().execution_count + 1;
 
-            "execute_request" => {
-                let code   = msg.content["code"].as_str().unwrap_or("").to_string();
-                let silent = msg.content["silent"].as_bool().unwrap_or(false);
+                    if !silent {
+                        let input_msg = JupyterMessage {
+                            identities: vec![],
+                            header: make_header("execute_input", &session_id),
+                            parent_header: msg.header.clone(),
+                            metadata: json!({}),
+                            content: json!({ "code": code, "execution_count": exec_count }),
+                            buffers: vec![],
+                        };
+                        send_message(&iopub.lock().unwrap(), &input_msg, &key);
+                    }
 
-                let exec_count = state.lock().unwrap().execution_count + 1;
+                    let mut io = ShellIo {
+                        iopub: &iopub,
+                        comms: &comms,
+                        stdin: &stdin,
+                        interrupt: &interrupt,
+                        shutdown: &shutdown,
+                        key: &key,
+                        session_id: &session_id,
+                        parent: &msg,
+                        execution_count: exec_count,
+                        silent,
+                        allow_stdin: msg.content["allow_stdin"].as_bool().unwrap_or(false),
+                    };
+                    let (stdout, error) = state.lock().unwrap().execute(&code, &mut io);
+                    let final_count = state.lock().unwrap().execution_count;
+                    // As in IPython, expressions are only evaluated after a cell that succeeded.
+                    let user_expressions = match (&error, msg.content["user_expressions"].as_object()) {
+                        (None, Some(expressions)) => state.lock().unwrap().user_expressions(expressions),
+                        _ => json!({}),
+                    };
 
-                if !silent {
-                    publish_status(&iopub, &key, &session_id, &msg, "busy");
+                    if store_history {
+                        history.lock().unwrap().record(final_count, &code, Some(&stdout));
+                    }
 
-                    let input_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("execute_input", &session_id),
+                    if let Some(error) = error.as_ref().filter(|_| !silent) {
+                        let error_msg = JupyterMessage {
+                            identities: vec![],
+                            header: make_header("error", &session_id),
+                            parent_header: msg.header.clone(),
+                            metadata: json!({}),
+                            content: json!({
+                                "ename": error.ename,
+                                "evalue": error.evalue,
+                                "traceback": error.traceback
+                            }),
+                            buffers: vec![],
+                        };
+                        send_message(&iopub.lock().unwrap(), &error_msg, &key);
+                    }
+
+                    let reply_content = if let Some(error) = &error {
+                        json!({
+                            "status": "error",
+                            "execution_count": final_count,
+                            "ename": error.ename,
+                            "evalue": error.evalue,
+                            "traceback": error.traceback
+                        })
+                    } else {
+                        json!({
+                            "status": "ok",
+                            "execution_count": final_count,
+                            "payload": [],
+                            "user_expressions": user_expressions
+                        })
+                    };
+
+                    let reply = JupyterMessage {
+                        identities: msg.identities.clone(),
+                        header: make_header("execute_reply", &session_id),
                         parent_header: msg.header.clone(),
                         metadata: json!({}),
-                        content: json!({ "code": code, "execution_count": exec_count }),
+                        content: reply_content,
                         buffers: vec![],
                     };
-                    send_message(&iopub.lock().unwrap(), &input_msg, &key);
+                    send_message(&replies, &reply, &key);
                 }
 
-                let (stdout, stderr, is_error) = state.lock().unwrap().execute(&code);
-                let final_count = state.lock().unwrap().execution_count;
-
-                if !stdout.is_empty() && !silent {
-                    let stream_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("stream", &session_id),
+                "complete_request" => {
+                    let code = msg.content["code"].as_str().unwrap_or("");
+                    let cursor_pos = msg.content["cursor_pos"]
+                        .as_u64()
+                        .map_or(code.chars().count(), |p| p as usize);
+                    let content = completion::complete(code, cursor_pos, &state.lock().unwrap().preamble);
+                    let reply = JupyterMessage {
+                        identities: msg.identities.clone(),
+                        header: make_header("complete_reply", &session_id),
                         parent_header: msg.header.clone(),
                         metadata: json!({}),
-                        content: json!({ "name": "stdout", "text": stdout }),
+                        content,
                         buffers: vec![],
                     };
-                    send_message(&iopub.lock().unwrap(), &stream_msg, &key);
+                    send_message(&replies, &reply, &key);
                 }
 
-                if is_error && !silent {
-                    if !stderr.is_empty() {
-                        let stream_msg = JupyterMessage {
-                            identities: vec![],
-                            header: make_header("stream", &session_id),
-                            parent_header: msg.header.clone(),
-                            metadata: json!({}),
-                            content: json!({ "name": "stderr", "text": stderr }),
-                            buffers: vec![],
-                        };
-                        send_message(&iopub.lock().unwrap(), &stream_msg, &key);
-                    }
-                    let error_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("error", &session_id),
+                "inspect_request" => {
+                    let code = msg.content["code"].as_str().unwrap_or("");
+                    let cursor_pos = msg.content["cursor_pos"]
+                        .as_u64()
+                        .map_or(code.chars().count(), |p| p as usize);
+                    let content = inspection::inspect(code, cursor_pos, &state.lock().unwrap().preamble);
+                    let reply = JupyterMessage {
+                        identities: msg.identities.clone(),
+                        header: make_header("inspect_reply", &session_id),
                         parent_header: msg.header.clone(),
                         metadata: json!({}),
-                        content: json!({
-                            "ename": "ArturoError",
-                            "evalue": "Arturo error",
-                            "traceback": stderr.lines().collect::<Vec<_>>()
-                        }),
+                        content,
                         buffers: vec![],
                     };
-                    send_message(&iopub.lock().unwrap(), &error_msg, &key);
-                } else if !stderr.is_empty() && !silent {
-                    let stream_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("stream", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({ "name": "stderr", "text": stderr }),
-                        buffers: vec![],
+                    send_message(&replies, &reply, &key);
+                }
+
+                "comm_open" | "comm_msg" | "comm_close" => {
+                    let mut io = ShellIo {
+                        iopub: &iopub,
+                        comms: &comms,
+                        stdin: &stdin,
+                        interrupt: &interrupt,
+                        shutdown: &shutdown,
+                        key: &key,
+                        session_id: &session_id,
+                        parent: &msg,
+                        execution_count: 0,
+                        silent: false,
+                        allow_stdin: false,
                     };
-                    send_message(&iopub.lock().unwrap(), &stream_msg, &key);
+                    if let Some(call) = call {
+                        state.lock().unwrap().run_handler(&call, &mut io);
+                    }
                 }
 
-                let reply_content = if is_error {
-                    json!({
-                        "status": "error",
-                        "execution_count": final_count,
-                        "ename": "ArturoError",
-                        "evalue": "Arturo error",
-                        "traceback": stderr.lines().collect::<Vec<_>>()
-                    })
-                } else {
-                    json!({
-                        "status": "ok",
-                        "execution_count": final_count,
-                        "payload": [],
-                        "user_expressions": {}
-                    })
-                };
+                other => eprintln!("[arturo-kernel] Unhandled shell msg: {other}"),
+            }
+            publish_status(&iopub, &key, &session_id, &msg, "idle");
+        })
+    };
 
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("execute_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: reply_content,
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+    // Shell loop — main thread. Requests that do not need the session are
+    // answered here, so they get through while a cell runs.
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut items = [shell.as_poll_item(zmq::POLLIN), executor.as_poll_item(zmq::POLLIN)];
+        if zmq::poll(&mut items, SHUTDOWN_POLL_MS.into()).is_err() {
+            continue;
+        }
+        let (request, reply) = (items[0].is_readable(), items[1].is_readable());
 
-                if !silent {
-                    publish_status(&iopub, &key, &session_id, &msg, "idle");
-                }
+        if reply {
+            if let Ok(frames) = executor.recv_multipart(0) {
+                shell.send_multipart(frames, 0).ok();
             }
+        }
+        if !request {
+            continue;
+        }
+        let Some(msg) = recv_message(&shell, &key, seen.as_deref()) else {
+            continue;
+        };
 
+        let msg_type = msg.header["msg_type"].as_str().unwrap_or("").to_string();
+        eprintln!("[arturo-kernel] shell <- {msg_type}");
+
+        // The registry is updated as a comm message arrives, so comm_info
+        // sees it at once; only the handler call waits for the session.
+        let call = match msg_type.as_str() {
+            "comm_open" | "comm_msg" | "comm_close" => comms.lock().unwrap().dispatch(&msg_type, &msg.content),
+            _ => None,
+        };
+        let (reply_type, content) = match msg_type.as_str() {
+            "kernel_info_request" => ("kernel_info_reply", kernel_info_content()),
+            "comm_info_request" => (
+                "comm_info_reply",
+                json!({
+                    "status": "ok",
+                    "comms": comms.lock().unwrap().info(msg.content["target_name"].as_str()),
+                }),
+            ),
             "is_complete_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("is_complete_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "complete" }),
-                    buffers: vec![],
+                let code = msg.content["code"].as_str().unwrap_or("");
+                let content = match lexer::completeness(code) {
+                    Completeness::Complete => json!({ "status": "complete" }),
+                    Completeness::Incomplete(indent) => json!({ "status": "incomplete", "indent": indent }),
+                    Completeness::Invalid => json!({ "status": "invalid" }),
                 };
-                send_message(&shell, &reply, &key);
+                ("is_complete_reply", content)
             }
-
-            "comm_info_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("comm_info_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "ok", "comms": {} }),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+            "history_request" => ("history_reply", history.lock().unwrap().reply(&msg.content)),
+            "comm_open" | "comm_msg" | "comm_close" if call.is_none() => {
+                publish_status(&iopub, &key, &session_id, &msg, "busy");
+                let comm_id = msg.content["comm_id"].as_str().unwrap_or("");
+                if msg_type == "comm_open" {
+                    // Nothing in the session handles this target: the
+                    // front-end should not expect the comm to stay open.
+                    let content = json!({ "comm_id": comm_id, "data": {} });
+                    publish_comm(&iopub, &key, &session_id, &msg, "comm_close", content, json!({}));
+                } else {
+                    eprintln!("[arturo-kernel] No handler for comm {comm_id}");
+                }
+                publish_status(&iopub, &key, &session_id, &msg, "idle");
+                continue;
             }
-
-            "history_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("history_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "ok", "history": [] }),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+            // The executor publishes the status of the requests it runs.
+            _ => {
+                jobs.send((msg, call)).ok();
+                continue;
             }
+        };
 
-            other => eprintln!("[arturo-kernel] Unhandled shell msg: {other}"),
-        }
+        publish_status(&iopub, &key, &session_id, &msg, "busy");
+        let reply = JupyterMessage {
+            identities: msg.identities.clone(),
+            header: make_header(reply_type, &session_id),
+            parent_header: msg.header.clone(),
+            metadata: json!({}),
+            content,
+            buffers: vec![],
+        };
+        send_message(&shell, &reply, &key);
+        publish_status(&iopub, &key, &session_id, &msg, "idle");
+    }
+
+    drop(jobs);
+    executor_thread.join().ok();
+    heartbeat_thread.join().ok();
+    control_thread.join().ok();
+    // Dropping the state stops the worker and removes the temp dir; the
+    // sockets close as they go out of scope.
+    drop(state);
+    eprintln!("[arturo-kernel] Shut down.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KernelState;
+    use crate::{
+        errors::ArturoError,
+        output::Stream,
+        testing::{self, Recorder, TempDir},
+    };
+    use std::{sync::Arc, thread, time::Duration};
+
+    /// The stdout of a cell that must have succeeded.
+    fn ok((stdout, error): (String, Option<ArturoError>)) -> String {
+        assert!(error.is_none(), "{error:?}");
+        stdout
+    }
+
+    #[test]
+    fn a_dead_worker_leaves_cells_to_run_after_the_preamble() {
+        let dir = TempDir::new();
+        let mut state = KernelState::new(testing::stub(&dir, testing::WORKER));
+        let mut io = Recorder::default();
+        assert_eq!(ok(state.execute("x: 1", &mut io)), "x: 1\n");
+
+        let (_, error) = state.execute("die", &mut io);
+        assert!(error.is_some());
+        assert!(state.worker.is_none());
+        assert!(io.text(Stream::Stderr).contains("later cells will re-run the accumulated preamble"));
+
+        let stdout = ok(state.execute("y", &mut io));
+        assert!(stdout.starts_with("x: 1\n"), "{stdout}");
+    }
+
+    #[test]
+    fn an_interrupted_worker_is_replaced_and_the_preamble_replayed() {
+        let dir = TempDir::new();
+        let mut state = KernelState::new(testing::stub(&dir, testing::WORKER));
+        let mut io = Recorder::default();
+        ok(state.execute("x: 1", &mut io));
+
+        let interrupt = Arc::clone(&state.interrupt);
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            interrupt.request();
+        });
+        let (_, error) = state.execute("hang", &mut io);
+        interrupter.join().unwrap();
+        assert_eq!(error.map(|e| e.ename), Some("KeyboardInterrupt".to_string()));
+        assert!(io.text(Stream::Stderr).contains("definitions were run again"));
+
+        assert!(state.worker.is_some());
+        assert_eq!(ok(state.execute("z: 2", &mut io)), "z: 2\n");
+    }
+
+    #[test]
+    fn reset_empties_the_session() {
+        let dir = TempDir::new();
+        let mut state = KernelState::new(testing::stub(&dir, testing::WORKER));
+        let mut io = Recorder::default();
+        ok(state.execute("x: 1", &mut io));
+        state.comms.lock().unwrap().register("counter", "onCounter");
+        state.comms.lock().unwrap().opened("c1", "counter");
+
+        ok(state.execute("%reset", &mut io));
+        assert_eq!(state.preamble.names().count(), 0);
+        assert!(state.comms.lock().unwrap().handler("counter").is_none());
+        assert_eq!(state.comms.lock().unwrap().info(None), serde_json::json!({}));
+        assert!(state.worker.is_some());
     }
 }
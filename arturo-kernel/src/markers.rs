@@ -0,0 +1,395 @@
+//! Sentinels the kernel exchanges with the Arturo programs it runs.
+//!
+//! The kernel's own markers embed a random token, so ordinary program output
+//! can never be mistaken for protocol traffic. The display and comm markers
+//! are fixed and documented, because user code prints them, so they only
+//! count at the start of a line. Markers always occupy the rest of a line on
+//! stdout: `<marker><payload>\n`.
+
+use crate::{
+    comms,
+    output::{Input, Stream, StreamBuffer},
+};
+use serde_json::{json, Value};
+use std::io::Write;
+use uuid::Uuid;
+
+/// Prefix of a rich display line, `#%arturo-display {"text/html": "…"}`, or
+/// of the `#%arturo-display-begin` / `#%arturo-display-end` lines around a
+/// multi-line JSON payload.
+pub const DISPLAY: &str = "#%arturo-display";
+
+/// Prefix of a comm line, `#%arturo-comm {"action": "msg", …}`; see
+/// [`crate::comms::Action`].
+pub const COMM: &str = "#%arturo-comm";
+
+#[derive(Debug, Clone)]
+pub struct Markers {
+    /// Written by the kernel after a cell's source (worker mode).
+    pub end: String,
+    /// Printed by the worker when a cell has finished; payload `ok` / `error`.
+    pub done: String,
+    /// Printed by `input` before it reads a line; payload is the prompt.
+    pub input: String,
+    /// Printed before and after the `as.code` form of a cell's value.
+    pub result: String,
+}
+
+impl Markers {
+    pub fn new() -> Markers {
+        let token = Uuid::new_v4().simple().to_string();
+        Markers {
+            end: format!("<<arturo-kernel-end-{token}>>"),
+            done: format!("<<arturo-kernel-done-{token}>>"),
+            input: format!("<<arturo-kernel-input-{token}>>"),
+            result: format!("<<arturo-kernel-result-{token}>>"),
+        }
+    }
+
+    /// One line of Arturo that reroutes `input` through the input marker,
+    /// keeping the real builtin as `arturoKernelInput`, and defines the
+    /// `arturoKernelResult` helper used by [`crate::results`].
+    pub fn prelude(&self) -> String {
+        format!(
+            "arturoKernelInput: var 'input  input: function [prompt] [ print [\"{input}\" prompt] arturoKernelInput \"\" ]  \
+             arturoKernelResult: function [values] [ unless null? last values [ print \"{result}\" print as.code last values print \"{result}\" ] ]",
+            input = self.input,
+            result = self.result,
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Scanned {
+    /// Ordinary program output.
+    Text(String),
+    /// `Markers::done` and its payload.
+    Done(String),
+    /// `Markers::input` and its prompt.
+    Input(String),
+    /// The JSON payload of a display line or block.
+    Display(String),
+    /// The JSON payload of a comm line.
+    Comm(String),
+    /// The `as.code` text printed between two `Markers::result` lines.
+    Result(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Done,
+    Input,
+    Display,
+    Comm,
+    Result,
+}
+
+/// Splits a stdout stream into program output and marker lines, holding back
+/// anything that could still turn out to be the start of a marker.
+#[derive(Debug)]
+pub struct MarkerScanner {
+    markers: Vec<(String, Kind)>,
+    pending: String,
+    /// Output collected between the opening and closing line of a display
+    /// or result block.
+    block: Option<(Kind, String)>,
+    /// Whether the start of `pending` is the start of a line.
+    line_start: bool,
+}
+
+impl MarkerScanner {
+    pub fn new(markers: &Markers) -> MarkerScanner {
+        MarkerScanner {
+            markers: vec![
+                (markers.done.clone(), Kind::Done),
+                (markers.input.clone(), Kind::Input),
+                (markers.result.clone(), Kind::Result),
+                (DISPLAY.to_string(), Kind::Display),
+                (COMM.to_string(), Kind::Comm),
+            ],
+            pending: String::new(),
+            block: None,
+            line_start: true,
+        }
+    }
+
+    pub fn feed(&mut self, text: &str) -> Vec<Scanned> {
+        self.pending.push_str(text);
+        let mut scanned = Vec::new();
+
+        loop {
+            let found = self
+                .markers
+                .iter()
+                .filter_map(|(marker, kind)| {
+                    let mut found = self.pending.match_indices(marker.as_str()).map(|(pos, _)| pos);
+                    let pos = match kind {
+                        Kind::Display | Kind::Comm => found.find(|&pos| self.starts_line(pos)),
+                        _ => found.next(),
+                    };
+                    pos.map(|pos| (pos, marker.len(), *kind))
+                })
+                .min_by_key(|(pos, _, _)| *pos);
+
+            let Some((pos, len, kind)) = found else {
+                break;
+            };
+            let Some(nl) = self.pending[pos + len..].find('\n') else {
+                // Marker line not complete yet; emit what precedes it.
+                if pos > 0 {
+                    let text: String = self.pending.drain(..pos).collect();
+                    self.text(text, &mut scanned);
+                }
+                return scanned;
+            };
+
+            if pos > 0 {
+                let text = self.pending[..pos].to_string();
+                self.text(text, &mut scanned);
+            }
+            let payload = self.pending[pos + len..pos + len + nl].trim_end_matches('\r').to_string();
+            self.pending.drain(..pos + len + nl + 1);
+            self.line_start = true;
+
+            match kind {
+                Kind::Done => {
+                    // The cell is over, so a block it left open was only output.
+                    if let Some((_, text)) = self.block.take().filter(|(_, text)| !text.is_empty()) {
+                        scanned.push(Scanned::Text(text));
+                    }
+                    scanned.push(Scanned::Done(strip_space(payload)));
+                }
+                Kind::Input => scanned.push(Scanned::Input(strip_space(payload))),
+                Kind::Display => match payload.trim() {
+                    "-begin" => self.block = Some((Kind::Display, String::new())),
+                    "-end" => scanned.extend(self.close_block(Kind::Display).map(Scanned::Display)),
+                    json => scanned.push(Scanned::Display(json.to_string())),
+                },
+                Kind::Comm => scanned.push(Scanned::Comm(payload.trim().to_string())),
+                Kind::Result => match self.close_block(Kind::Result) {
+                    Some(text) => scanned.push(Scanned::Result(text.trim_end_matches('\n').to_string())),
+                    None => self.block = Some((Kind::Result, String::new())),
+                },
+            }
+        }
+
+        let held = self
+            .markers
+            .iter()
+            .map(|(marker, _)| prefix_overlap(&self.pending, marker))
+            .max()
+            .unwrap_or(0);
+        let safe = self.pending.len() - held;
+        if safe > 0 {
+            let text: String = self.pending.drain(..safe).collect();
+            self.text(text, &mut scanned);
+        }
+        scanned
+    }
+
+    /// Whatever is still held back, once the stream has ended.
+    pub fn finish(&mut self) -> String {
+        let block = self.block.take().map(|(_, text)| text).unwrap_or_default();
+        block + &std::mem::take(&mut self.pending)
+    }
+
+    fn close_block(&mut self, kind: Kind) -> Option<String> {
+        match self.block.take() {
+            Some((open, text)) if open == kind => Some(text),
+            other => {
+                self.block = other;
+                None
+            }
+        }
+    }
+
+    fn starts_line(&self, pos: usize) -> bool {
+        match pos {
+            0 => self.line_start,
+            _ => self.pending[..pos].ends_with('\n'),
+        }
+    }
+
+    fn text(&mut self, text: String, scanned: &mut Vec<Scanned>) {
+        self.line_start = text.ends_with('\n');
+        match &mut self.block {
+            Some((_, block)) => block.push_str(&text),
+            None => scanned.push(Scanned::Text(text)),
+        }
+    }
+}
+
+fn strip_space(payload: String) -> String {
+    match payload.strip_prefix(' ') {
+        Some(rest) => rest.to_string(),
+        None => payload,
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `marker`.
+fn prefix_overlap(text: &str, marker: &str) -> usize {
+    (1..marker.len())
+        .rev()
+        .find(|&k| text.ends_with(&marker[..k]))
+        .unwrap_or(0)
+}
+
+/// Act on one scanned piece of stdout: record and forward text, answer
+/// `input` prompts through `child_stdin`, publish displays, comm traffic and
+/// results.
+/// Returns the payload of a done marker.
+pub fn handle(
+    piece: Scanned,
+    stdout: &mut String,
+    child_stdin: &mut impl Write,
+    out: &mut StreamBuffer,
+) -> Option<String> {
+    match piece {
+        Scanned::Text(text) => {
+            stdout.push_str(&text);
+            out.push(Stream::Stdout, &text);
+        }
+        Scanned::Input(prompt) => {
+            let value = match out.input(&prompt) {
+                Input::Line(value) => value,
+                Input::Refused => {
+                    out.push(
+                        Stream::Stderr,
+                        "`input` was called but this front-end does not allow stdin; reading an empty line.\n",
+                    );
+                    String::new()
+                }
+                // Left waiting for its line until the watchdog stops it.
+                Input::Interrupted => return None,
+            };
+            writeln!(child_stdin, "{}", value.replace(['\r', '\n'], " "))
+                .and_then(|_| child_stdin.flush())
+                .ok();
+        }
+        Scanned::Display(payload) => match display_bundle(&payload) {
+            Ok((data, metadata)) => out.display(data, metadata),
+            Err(e) => out.push(Stream::Stderr, &format!("Invalid {DISPLAY} payload: {e}\n")),
+        },
+        Scanned::Comm(payload) => match comms::parse(&payload) {
+            Ok(action) => out.comm(action),
+            Err(e) => out.push(Stream::Stderr, &format!("Invalid {COMM} payload: {e}\n")),
+        },
+        Scanned::Result(text) => out.result(&text),
+        Scanned::Done(status) => return Some(status),
+    }
+    None
+}
+
+/// Parse a display payload: either a MIME bundle (`{"text/html": "…"}`) or
+/// `{"data": {…}, "metadata": {…}}`.
+fn display_bundle(payload: &str) -> Result<(Value, Value), String> {
+    let value: Value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    let Value::Object(mut object) = value else {
+        return Err("expected a JSON object".to_string());
+    };
+
+    let (data, metadata) = match object.remove("data") {
+        Some(data) => (data, object.remove("metadata").unwrap_or_else(|| json!({}))),
+        None => (Value::Object(object), json!({})),
+    };
+    match &data {
+        Value::Object(bundle) if !bundle.is_empty() => Ok((data, metadata)),
+        _ => Err("no MIME data".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{display_bundle, MarkerScanner, Markers, Scanned};
+    use serde_json::json;
+
+    fn scan(chunks: &[&str]) -> Vec<Scanned> {
+        let mut scanner = MarkerScanner::new(&Markers::new());
+        let mut scanned: Vec<Scanned> = chunks.iter().flat_map(|c| scanner.feed(c)).collect();
+        let rest = scanner.finish();
+        if !rest.is_empty() {
+            scanned.push(Scanned::Text(rest));
+        }
+        scanned
+    }
+
+    #[test]
+    fn display_line_split_across_chunks() {
+        let scanned = scan(&["before\n#%arturo-dis", "play {\"text/html\": \"<b>x</b>\"}\nafter\n"]);
+        assert_eq!(
+            scanned,
+            vec![
+                Scanned::Text("before\n".to_string()),
+                Scanned::Display("{\"text/html\": \"<b>x</b>\"}".to_string()),
+                Scanned::Text("after\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn display_block() {
+        let scanned = scan(&["#%arturo-display-begin\n{\n\"text/plain\": \"hi\"\n}\n#%arturo-display-end\n"]);
+        assert_eq!(scanned, vec![Scanned::Display("{\n\"text/plain\": \"hi\"\n}\n".to_string())]);
+    }
+
+    #[test]
+    fn comm_line() {
+        let scanned = scan(&["#%arturo-comm {\"action\": \"close\", \"comm_id\": \"c1\"}\n"]);
+        assert_eq!(scanned, vec![Scanned::Comm("{\"action\": \"close\", \"comm_id\": \"c1\"}".to_string())]);
+    }
+
+    #[test]
+    fn public_markers_only_start_a_line() {
+        let text = "print \"see the #%arturo-display docs\"\n";
+        assert_eq!(scan(&[text]), vec![Scanned::Text(text.to_string())]);
+
+        let scanned = scan(&["partial ", "#%arturo-comm {}\nline\n#%arturo-comm {}\n"]);
+        assert_eq!(
+            scanned,
+            vec![
+                Scanned::Text("partial ".to_string()),
+                Scanned::Text("#%arturo-comm {}\nline\n".to_string()),
+                Scanned::Comm("{}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn result_block() {
+        let markers = Markers::new();
+        let mut scanner = MarkerScanner::new(&markers);
+        let output = format!("1\n{0}\n[1 2]\n{0}\n", markers.result);
+        assert_eq!(
+            scanner.feed(&output),
+            vec![Scanned::Text("1\n".to_string()), Scanned::Result("[1 2]".to_string())]
+        );
+    }
+
+    #[test]
+    fn unterminated_block_is_output_when_the_cell_is_done() {
+        let markers = Markers::new();
+        let mut scanner = MarkerScanner::new(&markers);
+        let output = format!("#%arturo-display-begin\nhello\n{} ok\n", markers.done);
+        assert_eq!(
+            scanner.feed(&output),
+            vec![Scanned::Text("hello\n".to_string()), Scanned::Done("ok".to_string())]
+        );
+        assert_eq!(scanner.finish(), "");
+    }
+
+    #[test]
+    fn bundle_with_metadata() {
+        let (data, metadata) =
+            display_bundle(r#"{"data": {"image/png": "AAAA"}, "metadata": {"image/png": {"width": 10}}}"#)
+                .unwrap();
+        assert_eq!(data, json!({ "image/png": "AAAA" }));
+        assert_eq!(metadata, json!({ "image/png": { "width": 10 } }));
+    }
+
+    #[test]
+    fn invalid_payloads_are_rejected() {
+        assert!(display_bundle("not json").is_err());
+        assert!(display_bundle("[1, 2]").is_err());
+        assert!(display_bundle("{}").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Incremental capture of a child's stdout/stderr.
+//!
+//! Pipes are read on background threads in whatever chunks the OS hands
+//! over and forwarded as [`Event`]s, so output can be published on IOPub
+//! while the cell is still running. [`StreamBuffer`] batches that output:
+//! complete lines go out as soon as the flush interval allows, and a partial
+//! line (a progress bar written with `prints`) is flushed by the timer.
+
+use crate::comms::Action;
+use serde_json::Value;
+use std::{
+    io::Read,
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Minimum time between two `stream` messages for the same cell, and the
+/// period at which a pending partial line is flushed.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    /// The `name` field of a Jupyter `stream` message.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Where a running cell's output, input prompts and rich displays go —
+/// in the kernel, the IOPub and stdin channels of the requesting front-end.
+pub trait CellIo {
+    fn output(&mut self, stream: Stream, text: &str);
+    /// Ask for a line of input.
+    fn input(&mut self, prompt: &str) -> Input;
+    /// Publish a MIME bundle.
+    fn display(&mut self, data: Value, metadata: Value);
+    /// Publish the `text/plain` value of the cell's last expression.
+    fn result(&mut self, text: &str);
+    /// Act on a comm line from Arturo.
+    fn comm(&mut self, action: Action);
+}
+
+/// The answer to an `input` prompt.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    Line(String),
+    /// The front-end does not allow stdin.
+    Refused,
+    /// An interrupt or shutdown arrived first; the watchdog stops the cell.
+    Interrupted,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Output(Stream, String),
+    Closed,
+}
+
+/// Forward a child pipe to `tx` on a background thread.
+pub fn forward<R>(pipe: R, stream: Stream, tx: Sender<Event>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut pipe = pipe;
+        let mut buf = [0u8; 8192];
+        // Bytes of a UTF-8 sequence split across two reads.
+        let mut carry: Vec<u8> = Vec::new();
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            carry.extend_from_slice(&buf[..n]);
+            let text = match std::str::from_utf8(&carry) {
+                Ok(text) => {
+                    let text = text.to_string();
+                    carry.clear();
+                    text
+                }
+                Err(e) if e.error_len().is_none() => {
+                    let valid = e.valid_up_to();
+                    let text = String::from_utf8_lossy(&carry[..valid]).into_owned();
+                    carry.drain(..valid);
+                    text
+                }
+                Err(_) => String::from_utf8_lossy(&std::mem::take(&mut carry)).into_owned(),
+            };
+            if !text.is_empty() && tx.send(Event::Output(stream, text)).is_err() {
+                return;
+            }
+        }
+        if !carry.is_empty() {
+            tx.send(Event::Output(stream, String::from_utf8_lossy(&carry).into_owned())).ok();
+        }
+        tx.send(Event::Closed).ok();
+    });
+}
+
+/// Batches output on its way to a [`CellIo`]. Input prompts and displays
+/// also go through the buffer so they appear after the output before them.
+pub struct StreamBuffer<'a> {
+    io: &'a mut dyn CellIo,
+    stdout: String,
+    stderr: String,
+    last_flush: Instant,
+}
+
+impl<'a> StreamBuffer<'a> {
+    pub fn new(io: &'a mut dyn CellIo) -> Self {
+        StreamBuffer {
+            io,
+            stdout: String::new(),
+            stderr: String::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Queue `text`; complete lines are flushed if the interval has passed.
+    pub fn push(&mut self, stream: Stream, text: &str) {
+        self.pending(stream).push_str(text);
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush_lines(Stream::Stderr);
+            self.flush_lines(Stream::Stdout);
+        }
+    }
+
+    /// Flush everything, partial lines included, once the interval has passed.
+    pub fn tick(&mut self) {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for stream in [Stream::Stderr, Stream::Stdout] {
+            let text = std::mem::take(self.pending(stream));
+            self.emit(stream, &text);
+        }
+    }
+
+    pub fn input(&mut self, prompt: &str) -> Input {
+        self.flush();
+        self.io.input(prompt)
+    }
+
+    pub fn display(&mut self, data: Value, metadata: Value) {
+        self.flush();
+        self.io.display(data, metadata);
+    }
+
+    pub fn result(&mut self, text: &str) {
+        self.flush();
+        self.io.result(text);
+    }
+
+    pub fn comm(&mut self, action: Action) {
+        self.flush();
+        self.io.comm(action);
+    }
+
+    fn flush_lines(&mut self, stream: Stream) {
+        let pending = self.pending(stream);
+        let Some(nl) = pending.rfind('\n') else {
+            return;
+        };
+        let lines: String = pending.drain(..=nl).collect();
+        self.emit(stream, &lines);
+    }
+
+    fn emit(&mut self, stream: Stream, text: &str) {
+        if !text.is_empty() {
+            self.io.output(stream, text);
+            self.last_flush = Instant::now();
+        }
+    }
+
+    fn pending(&mut self, stream: Stream) -> &mut String {
+        match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        }
+    }
+}
+
+impl Drop for StreamBuffer<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward, Event, Stream, StreamBuffer, FLUSH_INTERVAL};
+    use crate::testing::Recorder;
+    use std::{
+        io::Read,
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    /// Pretend the last flush just happened, however slow the test runs.
+    fn hold(out: &mut StreamBuffer) {
+        out.last_flush = Instant::now() + Duration::from_secs(3600);
+    }
+
+    /// Pretend the flush interval has passed.
+    fn expire(out: &mut StreamBuffer) {
+        out.last_flush = Instant::now() - FLUSH_INTERVAL;
+    }
+
+    #[test]
+    fn lines_wait_for_the_flush_interval() {
+        let mut io = Recorder::default();
+        {
+            let mut out = StreamBuffer::new(&mut io);
+            hold(&mut out);
+            out.push(Stream::Stdout, "a\n");
+            out.push(Stream::Stdout, "b\nc");
+            expire(&mut out);
+            out.push(Stream::Stdout, "d\ne");
+            hold(&mut out);
+            out.push(Stream::Stdout, "f\n");
+        }
+        assert_eq!(
+            io.streams,
+            [(Stream::Stdout, "a\nb\ncd\n".to_string()), (Stream::Stdout, "ef\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn tick_flushes_partial_lines() {
+        let mut io = Recorder::default();
+        let mut out = StreamBuffer::new(&mut io);
+        hold(&mut out);
+        out.push(Stream::Stdout, "50%");
+        out.push(Stream::Stderr, "warn");
+        out.tick();
+        expire(&mut out);
+        out.tick();
+        hold(&mut out);
+        out.push(Stream::Stdout, "..");
+        out.tick();
+        drop(out);
+        assert_eq!(
+            io.streams,
+            [
+                (Stream::Stderr, "warn".to_string()),
+                (Stream::Stdout, "50%".to_string()),
+                (Stream::Stdout, "..".to_string()),
+            ]
+        );
+    }
+
+    /// A pipe that hands over its bytes in the given chunks.
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn received(chunks: &[&[u8]]) -> Vec<String> {
+        let (tx, rx) = mpsc::channel();
+        forward(Chunks(chunks.iter().map(|c| c.to_vec()).collect()), Stream::Stdout, tx);
+        rx.iter()
+            .take_while(|event| !matches!(event, Event::Closed))
+            .map(|event| match event {
+                Event::Output(_, text) => text,
+                Event::Closed => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        let text = "héllo → ✓".as_bytes();
+        let (first, rest) = text.split_at(2);
+        let (second, third) = rest.split_at(7);
+        assert_eq!(received(&[first, second, third]), ["h", "éllo ", "→ ✓"]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(received(&[b"ok \xff!"]), ["ok \u{fffd}!"]);
+        assert_eq!(received(&[b"cut \xe2\x86"]), ["cut ", "\u{fffd}"]);
+    }
+}
+
@@ -0,0 +1,432 @@
+//! The session preamble: top-level assignments picked out of successful
+//! cells, kept as a symbol table so they can be replayed in front of later
+//! cells.
+
+use crate::{
+    lexer::{self, Token, TokenKind},
+    sourcemap::{Location, SourceMap},
+};
+use std::collections::HashSet;
+
+/// A top-level `name: value` statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub name: String,
+    pub source: String,
+    /// One-based line of the cell on which `source` starts.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Definition {
+    name: String,
+    source: String,
+    /// Where `source` came from.
+    origin: Location,
+    /// Every word the right-hand side mentions, including `name` itself for
+    /// updates like `x: x + 1`.
+    references: HashSet<String>,
+}
+
+/// Ordered definitions, at most one live definition per name.
+///
+/// Redefining a name appends the new definition and drops the old one —
+/// unless a surviving later definition still refers to it (`y: x + 1`
+/// between `x: 1` and `x: 2`), in which case the old one is kept so the
+/// replayed program computes the same `y`. Cells that fail are never
+/// recorded.
+#[derive(Debug, Default)]
+pub struct Preamble {
+    definitions: Vec<Definition>,
+}
+
+impl Preamble {
+    /// Record the assignments of `code`, which ran successfully; `map`
+    /// places its lines in their cell.
+    pub fn record(&mut self, code: &str, map: &SourceMap) {
+        let assignments = extract_assignments(code);
+        if assignments.is_empty() {
+            return;
+        }
+        for Assignment { name, source, line } in assignments {
+            let Some(origin) = map.locate(line) else {
+                continue;
+            };
+            let references = referenced_words(&source, &name);
+            self.definitions.push(Definition { name, source, origin, references });
+        }
+        self.prune();
+    }
+
+    /// Drop the definition containing `location`, returning its name.
+    ///
+    /// Used when replaying the preamble fails inside an earlier cell's
+    /// definition, so one broken definition does not fail every later cell.
+    pub fn discard(&mut self, location: Location) -> Option<String> {
+        let index = self.definitions.iter().position(|d| {
+            d.origin.cell == location.cell
+                && (d.origin.line..d.origin.line + d.source.lines().count()).contains(&location.line)
+        })?;
+        let removed = self.definitions.remove(index);
+        self.prune();
+        Some(removed.name)
+    }
+
+    /// Drop definitions that nothing needs any more, walking backwards so
+    /// each name is satisfied by its latest preceding definition.
+    fn prune(&mut self) {
+        let mut defined_later: HashSet<String> = HashSet::new();
+        let mut wanted: HashSet<String> = HashSet::new();
+        let mut keep = vec![false; self.definitions.len()];
+
+        for (i, def) in self.definitions.iter().enumerate().rev() {
+            let latest = defined_later.insert(def.name.clone());
+            if latest || wanted.remove(&def.name) {
+                keep[i] = true;
+                wanted.extend(def.references.iter().cloned());
+            }
+        }
+
+        let mut keep = keep.into_iter();
+        self.definitions.retain(|_| keep.next().unwrap_or(false));
+    }
+
+    /// Names with a live definition, in definition order (a name may repeat
+    /// while an older definition is still needed).
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.definitions.iter().map(|d| d.name.as_str())
+    }
+
+    /// Source of the live definition of `name`, if any.
+    pub fn definition(&self, name: &str) -> Option<&str> {
+        self.definitions
+            .iter()
+            .rev()
+            .find(|d| d.name == name)
+            .map(|d| d.source.as_str())
+    }
+
+    /// The preamble as Arturo source, one definition after another.
+    pub fn render(&self) -> String {
+        self.definitions.iter().map(|d| d.source.as_str()).collect::<Vec<_>>().join("\n")
+    }
+
+    /// `prelude` (kernel-injected, may be empty), the preamble, then `code`
+    /// (placed in its cell by `code_map`), and where each line of the result
+    /// came from.
+    pub fn program(&self, code: &str, code_map: &SourceMap, prelude: &str) -> (String, SourceMap) {
+        let mut program = String::new();
+        let mut map = SourceMap::default();
+        if !prelude.is_empty() {
+            program.push_str(prelude);
+            program.push('\n');
+            map.skip(prelude);
+        }
+        for def in &self.definitions {
+            program.push_str(&def.source);
+            program.push('\n');
+            map.push(def.origin.cell, def.origin.line, &def.source);
+        }
+        program.push_str(code);
+        map.append(code_map);
+        (program, map)
+    }
+}
+
+/// Names mentioned by the value part of an assignment's source.
+fn referenced_words(source: &str, name: &str) -> HashSet<String> {
+    let Ok(tokens) = lexer::tokenize(source) else {
+        return HashSet::new();
+    };
+    let mut words = HashSet::new();
+    let mut seen_label = false;
+    for token in tokens {
+        let text = token.text(source);
+        match token.kind {
+            TokenKind::Label if !seen_label && text.trim_end_matches(':') == name => {
+                seen_label = true;
+            }
+            TokenKind::Word => {
+                words.insert(text.to_string());
+            }
+            TokenKind::Literal => {
+                words.insert(text.trim_start_matches('\'').to_string());
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Return every top-level statement of `code` that starts with a `label:`,
+/// with its full source text (multi-line for blocks, dictionaries and `{ }`
+/// strings).
+///
+/// Statements are split on newlines at nesting depth zero, so labels inside
+/// dictionaries or function bodies are never mistaken for top-level
+/// assignments, and brackets inside strings and comments are ignored.
+pub fn extract_assignments(code: &str) -> Vec<Assignment> {
+    let Ok(tokens) = lexer::tokenize(code) else {
+        // An unterminated literal would make the cell fail anyway.
+        return Vec::new();
+    };
+
+    let mut result = Vec::new();
+    let mut depth = 0usize;
+    let mut statement: Vec<Token> = Vec::new();
+
+    for token in tokens {
+        match token.kind {
+            TokenKind::Newline if depth == 0 => {
+                result.extend(assignment_source(code, &statement));
+                statement.clear();
+                continue;
+            }
+            TokenKind::Open => depth += 1,
+            TokenKind::Close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        statement.push(token);
+    }
+    result.extend(assignment_source(code, &statement));
+
+    result
+}
+
+/// `statement` as an assignment, with source from the start of its first
+/// line up to its last significant token.
+fn assignment_source(code: &str, statement: &[Token]) -> Option<Assignment> {
+    let mut significant = statement
+        .iter()
+        .filter(|t| !matches!(t.kind, TokenKind::Comment | TokenKind::Newline));
+
+    let label = significant.next()?;
+    let last = significant.next_back()?;
+    if label.kind != TokenKind::Label {
+        return None;
+    }
+
+    let line_start = code[..label.start].rfind('\n').map_or(0, |i| i + 1);
+    Some(Assignment {
+        name: label.text(code).trim_end_matches(':').to_string(),
+        source: code[line_start..last.end].to_string(),
+        line: label.line + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Preamble;
+    use crate::sourcemap::SourceMap;
+
+    fn record(preamble: &mut Preamble, cell: u32, code: &str) {
+        preamble.record(code, &SourceMap::cell(cell, code));
+    }
+
+    fn extract_assignments(code: &str) -> Vec<String> {
+        super::extract_assignments(code).into_iter().map(|a| a.source).collect()
+    }
+
+    #[test]
+    fn simple_assignments() {
+        let code = "x: 1\nname: \"Arturo\"\nprint x";
+        assert_eq!(extract_assignments(code), ["x: 1", "name: \"Arturo\""]);
+    }
+
+    #[test]
+    fn side_effects_are_skipped() {
+        let code = "print \"hi\"\necho 1\nprints 2\ninspect x\n; y: 2";
+        assert!(extract_assignments(code).is_empty());
+    }
+
+    #[test]
+    fn bracket_inside_string_does_not_open_block() {
+        let code = "msg: \"see [1\"\ny: 2";
+        assert_eq!(extract_assignments(code), ["msg: \"see [1\"", "y: 2"]);
+    }
+
+    #[test]
+    fn bracket_inside_comment_does_not_open_block() {
+        let code = "a: 1 ; closes later ]\nb: [\n  1 ; [ not a block\n]\nc: 3";
+        assert_eq!(
+            extract_assignments(code),
+            ["a: 1", "b: [\n  1 ; [ not a block\n]", "c: 3"]
+        );
+    }
+
+    #[test]
+    fn multiline_function_is_one_statement() {
+        let code = "double: function [n] [\n    n * 2\n]\nprint double 21";
+        assert_eq!(
+            extract_assignments(code),
+            ["double: function [n] [\n    n * 2\n]"]
+        );
+    }
+
+    #[test]
+    fn multiline_curly_string() {
+        let code = "text: {\n  first [\n  second\n}\nz: 0";
+        assert_eq!(
+            extract_assignments(code),
+            ["text: {\n  first [\n  second\n}", "z: 0"]
+        );
+    }
+
+    #[test]
+    fn verbatim_and_guillemet_strings() {
+        let code = "v: {: ] } :}\ng: «[ unbalanced»\nh: 1";
+        assert_eq!(
+            extract_assignments(code),
+            ["v: {: ] } :}", "g: «[ unbalanced»", "h: 1"]
+        );
+    }
+
+    #[test]
+    fn labels_inside_dictionary_stay_in_dictionary() {
+        let code = "point: #[\n    x: 1\n    y: 2\n]\nprint point";
+        assert_eq!(extract_assignments(code), ["point: #[\n    x: 1\n    y: 2\n]"]);
+    }
+
+    #[test]
+    fn labels_inside_function_body_are_not_top_level() {
+        let code = "f: function [] [\n    tmp: 3\n    tmp + 1\n]";
+        assert_eq!(extract_assignments(code), [code]);
+    }
+
+    #[test]
+    fn char_literal_bracket() {
+        let code = "open: `[`\nclose: `]`";
+        assert_eq!(extract_assignments(code), ["open: `[`", "close: `]`"]);
+    }
+
+    #[test]
+    fn hyphenated_and_predicate_names() {
+        let code = "max-size: 10\nready?: true";
+        assert_eq!(extract_assignments(code), ["max-size: 10", "ready?: true"]);
+    }
+
+    #[test]
+    fn label_without_value_is_skipped() {
+        assert!(extract_assignments("x:\n; nothing").is_empty());
+    }
+
+    #[test]
+    fn trailing_comment_is_dropped() {
+        assert_eq!(extract_assignments("n: 5 ; five"), ["n: 5"]);
+    }
+
+    #[test]
+    fn stray_closer_does_not_swallow_later_lines() {
+        let code = "]\na: 1";
+        assert_eq!(extract_assignments(code), ["a: 1"]);
+    }
+
+    #[test]
+    fn unterminated_string_yields_nothing() {
+        assert!(extract_assignments("s: \"oops\nt: 1").is_empty());
+    }
+
+    #[test]
+    fn assignment_names() {
+        let names: Vec<_> = super::extract_assignments("ready?: true\nto-upper: 1")
+            .into_iter()
+            .map(|a| a.name)
+            .collect();
+        assert_eq!(names, ["ready?", "to-upper"]);
+    }
+
+    #[test]
+    fn redefinition_replaces_earlier_definition() {
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "x: 1");
+        record(&mut preamble, 2, "x: 2");
+        assert_eq!(preamble.render(), "x: 2");
+    }
+
+    #[test]
+    fn redefinition_keeps_value_needed_by_dependents() {
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "x: 1");
+        record(&mut preamble, 2, "y: x + 1");
+        record(&mut preamble, 3, "x: 10");
+        assert_eq!(preamble.render(), "x: 1\ny: x + 1\nx: 10");
+
+        record(&mut preamble, 4, "y: 0");
+        assert_eq!(preamble.render(), "x: 10\ny: 0");
+    }
+
+    #[test]
+    fn self_referencing_update_keeps_previous_value() {
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "n: 1");
+        record(&mut preamble, 2, "n: n + 1");
+        assert_eq!(preamble.render(), "n: 1\nn: n + 1");
+    }
+
+    #[test]
+    fn redefinition_within_one_cell() {
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "a: 1\nb: 2\na: 3");
+        assert_eq!(preamble.render(), "b: 2\na: 3");
+    }
+
+    #[test]
+    fn dependency_order_is_preserved() {
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "base: 2");
+        record(&mut preamble, 2, "square: function [n] [n * base]");
+        record(&mut preamble, 3, "base: 3");
+        assert_eq!(
+            preamble.render(),
+            "base: 2\nsquare: function [n] [n * base]\nbase: 3"
+        );
+    }
+
+    #[test]
+    fn program_maps_lines_back_to_cells() {
+        use crate::sourcemap::Location;
+
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "print 0\nf: function [x] [\n    x\n]");
+        record(&mut preamble, 2, "y: 2");
+        let code = "print f y\nz";
+        let (program, map) = preamble.program(code, &SourceMap::cell(3, code), "; prelude");
+        assert_eq!(program, "; prelude\nf: function [x] [\n    x\n]\ny: 2\nprint f y\nz");
+        assert_eq!(map.locate(1), None);
+        assert_eq!(map.locate(2), Some(Location { cell: 1, line: 2 }));
+        assert_eq!(map.locate(4), Some(Location { cell: 1, line: 4 }));
+        assert_eq!(map.locate(5), Some(Location { cell: 2, line: 1 }));
+        assert_eq!(map.locate(7), Some(Location { cell: 3, line: 2 }));
+        assert_eq!(map.locate(8), None);
+    }
+
+    #[test]
+    fn loaded_file_maps_to_its_magic_line() {
+        use crate::sourcemap::Location;
+
+        let mut cell = SourceMap::default();
+        cell.push_loaded(2, 1, "a: 1\nb: 2\nc: 3");
+        cell.push(2, 2, "d: 4\nbad");
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "x: 0");
+        let (_, map) = preamble.program("a: 1\nb: 2\nc: 3\nd: 4\nbad", &cell, "");
+        assert_eq!(map.locate(3), Some(Location { cell: 2, line: 1 }));
+        assert_eq!(map.locate(5), Some(Location { cell: 2, line: 2 }));
+        assert_eq!(map.locate(6), Some(Location { cell: 2, line: 3 }));
+
+        preamble.record("a: 1\nb: 2\nc: 3\nd: 4", &cell);
+        assert_eq!(preamble.discard(Location { cell: 2, line: 2 }), Some("d".to_string()));
+    }
+
+    #[test]
+    fn discard_removes_failing_definition() {
+        use crate::sourcemap::Location;
+
+        let mut preamble = Preamble::default();
+        record(&mut preamble, 1, "a: 1\nb: broken");
+        record(&mut preamble, 2, "c: 3");
+        assert_eq!(preamble.discard(Location { cell: 1, line: 2 }), Some("b".to_string()));
+        assert_eq!(preamble.render(), "a: 1\nc: 3");
+        assert_eq!(preamble.discard(Location { cell: 1, line: 2 }), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! `execute_result` for the value of a cell's last expression.
+//!
+//! The last top-level statement of a cell is rewritten to
+//! `arturoKernelResult @[<statement>]`. `@` collects whatever the statement
+//! leaves on the stack, so statements without a value (`print`, `loop`, …)
+//! simply produce nothing, and the helper from [`Markers::prelude`] prints the
+//! `as.code` form of the last value between two result markers.
+//!
+//! [`Markers::prelude`]: crate::markers::Markers::prelude
+
+use crate::lexer::{self, Token, TokenKind};
+
+/// `code` with its last statement wrapped for capture, or `None` if the cell
+/// does not end in an expression (empty, an assignment, or untokenizable).
+pub fn capture_last(code: &str) -> Option<String> {
+    let tokens = lexer::tokenize(code).ok()?;
+
+    // Statements are split at top-level newlines, as in `extract_assignments`;
+    // a line starting with an operator or attribute continues the one above.
+    let mut statements: Vec<Vec<Token>> = vec![Vec::new()];
+    let mut depth = 0usize;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Comment => continue,
+            TokenKind::Newline if depth == 0 => {
+                statements.push(Vec::new());
+                continue;
+            }
+            TokenKind::Symbol | TokenKind::Attribute if depth == 0 => {
+                let current = statements.last().expect("never empty");
+                if current.is_empty() && statements.len() > 1 {
+                    statements.pop();
+                    while statements.last().is_some_and(Vec::is_empty) && statements.len() > 1 {
+                        statements.pop();
+                    }
+                }
+            }
+            TokenKind::Open => depth += 1,
+            TokenKind::Close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        statements.last_mut().expect("never empty").push(token);
+    }
+
+    // A statement left unfinished by a trailing operator (`1 +`) or an
+    // attribute waiting for its value (`.with:`) goes on with the next line,
+    // unless that line is an assignment. One ending in a word is complete:
+    // the word may well take no arguments.
+    let mut merged: Vec<Vec<Token>> = Vec::new();
+    for statement in statements.into_iter().filter(|s| s.iter().any(|t| t.kind != TokenKind::Newline)) {
+        let first = statement.iter().find(|t| t.kind != TokenKind::Newline).map(|t| t.kind);
+        match merged.last_mut() {
+            Some(previous) if first != Some(TokenKind::Label) && previous.last().is_some_and(|t| unfinished(t, code)) => {
+                previous.extend(statement);
+            }
+            _ => merged.push(statement),
+        }
+    }
+
+    let last = merged.last()?;
+    let first = last.iter().find(|t| t.kind != TokenKind::Newline)?;
+    let end = last.iter().rev().find(|t| t.kind != TokenKind::Newline)?;
+    if first.kind == TokenKind::Label {
+        return None;
+    }
+
+    Some(format!(
+        "{}arturoKernelResult @[{}]{}",
+        &code[..first.start],
+        &code[first.start..end.end],
+        &code[end.end..]
+    ))
+}
+
+/// Whether a statement ending in `token` needs more to be complete.
+fn unfinished(token: &Token, code: &str) -> bool {
+    match token.kind {
+        TokenKind::Symbol => true,
+        TokenKind::Attribute => token.text(code).ends_with(':'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capture_last;
+
+    #[test]
+    fn wraps_the_last_expression() {
+        assert_eq!(capture_last("x: 1\nx + 1").as_deref(), Some("x: 1\narturoKernelResult @[x + 1]"));
+    }
+
+    #[test]
+    fn keeps_trailing_comments_and_lines() {
+        assert_eq!(
+            capture_last("x: 1\nx + 1 ; the answer\n\n").as_deref(),
+            Some("x: 1\narturoKernelResult @[x + 1] ; the answer\n\n")
+        );
+    }
+
+    #[test]
+    fn multi_line_statements() {
+        let code = "map [1 2 3] 'n [\n    n * 2\n]";
+        assert_eq!(capture_last(code), Some(format!("arturoKernelResult @[{code}]")));
+
+        let pipeline = "x: 1\n[1 2 3]\n    | map 'n -> n * 2\n";
+        assert_eq!(
+            capture_last(pipeline).as_deref(),
+            Some("x: 1\narturoKernelResult @[[1 2 3]\n    | map 'n -> n * 2]\n")
+        );
+    }
+
+    #[test]
+    fn unfinished_statements_continue_on_the_next_line() {
+        assert_eq!(capture_last("1 +\n\n2").as_deref(), Some("arturoKernelResult @[1 +\n\n2]"));
+        assert_eq!(
+            capture_last("split.by:\n    \",\" \"a,b\"").as_deref(),
+            Some("arturoKernelResult @[split.by:\n    \",\" \"a,b\"]")
+        );
+        assert_eq!(capture_last("x: 1 +\ny: 2"), None);
+    }
+
+    #[test]
+    fn a_trailing_word_ends_its_statement() {
+        assert_eq!(capture_last("x: 1\ny\nprint x").as_deref(), Some("x: 1\ny\narturoKernelResult @[print x]"));
+        assert_eq!(
+            capture_last("print x\nlength s").as_deref(),
+            Some("print x\narturoKernelResult @[length s]")
+        );
+    }
+
+    #[test]
+    fn assignments_and_empty_cells_are_left_alone() {
+        assert_eq!(capture_last("x: 1 + 2"), None);
+        assert_eq!(capture_last("; just a comment\n"), None);
+        assert_eq!(capture_last(""), None);
+        assert_eq!(capture_last("print \"unterminated"), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! Message signatures and replay protection.
+//!
+//! Messages are signed with HMAC-SHA256 over their header, parent header,
+//! metadata and content frames; it is the only `signature_scheme` the kernel
+//! accepts. An empty key turns signing off.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
+
+pub const SCHEME: &str = "hmac-sha256";
+
+/// How many recent `msg_id`s [`SeenIds`] remembers.
+const REMEMBERED_IDS: usize = 10_000;
+
+/// Fail for a `signature_scheme` the kernel cannot sign with.
+pub fn check_scheme(scheme: &str) -> Result<(), String> {
+    if scheme == SCHEME {
+        Ok(())
+    } else {
+        Err(format!("Unsupported signature_scheme `{scheme}`: arturo-kernel only supports {SCHEME}"))
+    }
+}
+
+/// The hex signature of `parts`, or an empty string without a key.
+pub fn sign(key: &[u8], parts: &[&[u8]]) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+    hex::encode(mac(key, parts).finalize().into_bytes())
+}
+
+/// Whether `signature` is the hex signature of `parts`. The digests are
+/// compared in constant time.
+pub fn verify(key: &[u8], parts: &[&[u8]], signature: &str) -> bool {
+    if key.is_empty() {
+        return true;
+    }
+    match hex::decode(signature) {
+        Ok(signature) => mac(key, parts).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// The most recent `msg_id`s received, to reject replayed messages.
+#[derive(Debug, Default)]
+pub struct SeenIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenIds {
+    /// Remember `msg_id`; `false` if it was seen before.
+    pub fn insert(&mut self, msg_id: &str) -> bool {
+        if !self.ids.insert(msg_id.to_string()) {
+            return false;
+        }
+        self.order.push_back(msg_id.to_string());
+        if self.order.len() > REMEMBERED_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_scheme, sign, verify, SeenIds};
+
+    #[test]
+    fn signatures_round_trip() {
+        let parts: [&[u8]; 2] = [b"{\"msg_id\":\"1\"}", b"{}"];
+        let signature = sign(b"secret", &parts);
+        assert_eq!(signature.len(), 64);
+        assert!(verify(b"secret", &parts, &signature));
+        assert!(!verify(b"other", &parts, &signature));
+        assert!(!verify(b"secret", &[b"{}", b"{}"], &signature));
+        assert!(!verify(b"secret", &parts, "not hex"));
+        assert!(!verify(b"secret", &parts, ""));
+    }
+
+    #[test]
+    fn no_key_no_signature() {
+        assert_eq!(sign(b"", &[b"{}"]), "");
+        assert!(verify(b"", &[b"{}"], "anything"));
+    }
+
+    #[test]
+    fn only_hmac_sha256() {
+        assert!(check_scheme("hmac-sha256").is_ok());
+        assert!(check_scheme("hmac-md5").is_err());
+        assert!(check_scheme("").is_err());
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let mut seen = SeenIds::default();
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        assert!(!seen.insert("a"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Mapping lines of the program Arturo runs back to the cells they came from.
+//!
+//! In per-cell mode the program is the preamble (definitions taken from
+//! earlier cells) followed by the current cell, so a line reported by Arturo
+//! may belong to any of them. Each contiguous run of lines is recorded as a
+//! segment pointing at its cell's execution count and starting line. A file
+//! pulled in by `%load` is a segment of its own, all of it standing for the
+//! `%load` line.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// Execution count of the cell, as shown in `In [n]`.
+    pub cell: u32,
+    /// One-based line within that cell.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// One-based first line in the program.
+    start: usize,
+    len: usize,
+    cell: u32,
+    cell_line: usize,
+    /// Every line stands for `cell_line` (loaded from a file).
+    loaded: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    segments: Vec<Segment>,
+    lines: usize,
+}
+
+impl SourceMap {
+    /// A program that is exactly one cell.
+    pub fn cell(cell: u32, code: &str) -> SourceMap {
+        let mut map = SourceMap::default();
+        map.push(cell, 1, code);
+        map
+    }
+
+    /// Append `text`, which starts at line `cell_line` of cell `cell`.
+    pub fn push(&mut self, cell: u32, cell_line: usize, text: &str) {
+        self.push_segment(cell, cell_line, text, false);
+    }
+
+    /// Append `text`, a file loaded by the magic on line `cell_line` of cell
+    /// `cell`.
+    pub fn push_loaded(&mut self, cell: u32, cell_line: usize, text: &str) {
+        self.push_segment(cell, cell_line, text, true);
+    }
+
+    fn push_segment(&mut self, cell: u32, cell_line: usize, text: &str, loaded: bool) {
+        let len = text.lines().count().max(1);
+        self.segments.push(Segment { start: self.lines + 1, len, cell, cell_line, loaded });
+        self.lines += len;
+    }
+
+    /// Append the program `other` maps.
+    pub fn append(&mut self, other: &SourceMap) {
+        for segment in &other.segments {
+            self.segments.push(Segment { start: self.lines + segment.start, ..*segment });
+        }
+        self.lines += other.lines;
+    }
+
+    /// Append `text` that belongs to no cell (kernel-injected code).
+    pub fn skip(&mut self, text: &str) {
+        self.lines += text.lines().count().max(1);
+    }
+
+    pub fn locate(&self, program_line: usize) -> Option<Location> {
+        self.segments
+            .iter()
+            .find(|s| (s.start..s.start + s.len).contains(&program_line))
+            .map(|s| Location {
+                cell: s.cell,
+                line: if s.loaded { s.cell_line } else { s.cell_line + (program_line - s.start) },
+            })
+    }
+}
@@ -0,0 +1,107 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    comms::Action,
+    config::Config,
+    output::{CellIo, Input, Stream},
+    preamble::Preamble,
+    sourcemap::SourceMap,
+};
+use serde_json::Value;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// A session that has run `code` as its first cell.
+pub fn session(code: &str) -> Preamble {
+    let mut preamble = Preamble::default();
+    preamble.record(code, &SourceMap::cell(1, code));
+    preamble
+}
+
+/// Records the output that reaches the front-end, and refuses input.
+#[derive(Default)]
+pub struct Recorder {
+    pub streams: Vec<(Stream, String)>,
+}
+
+impl Recorder {
+    /// Everything written to `stream`.
+    pub fn text(&self, stream: Stream) -> String {
+        self.streams.iter().filter(|(s, _)| *s == stream).map(|(_, text)| text.as_str()).collect()
+    }
+}
+
+impl CellIo for Recorder {
+    fn output(&mut self, stream: Stream, text: &str) {
+        self.streams.push((stream, text.to_string()));
+    }
+
+    fn input(&mut self, _prompt: &str) -> Input {
+        Input::Refused
+    }
+
+    fn display(&mut self, _data: Value, _metadata: Value) {}
+
+    fn result(&mut self, _text: &str) {}
+
+    fn comm(&mut self, _action: Action) {}
+}
+
+/// A fresh directory under the system temp dir, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let dir = env::temp_dir().join(format!("arturo-kernel-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// A configuration that runs the shell script `script`, written into `dir`,
+/// in place of Arturo. It gets Arturo's arguments as `$1`, `$2`, ….
+pub fn stub(dir: &TempDir, script: &str) -> Config {
+    let path = dir.path().join("arturo.sh");
+    fs::write(&path, script).unwrap();
+    Config { executable: "sh".to_string(), args: vec![path.display().to_string()], ..Config::default() }
+}
+
+/// A stand-in for Arturo that speaks the worker's protocol (see
+/// [`crate::worker`]), for use with [`stub`]. A cell's lines are echoed,
+/// except those mentioning `fail`, which fails the cell with `boom` on
+/// stderr after its done marker, `die`, which exits, or `hang`, which never
+/// finishes. Run with `-e`, it echoes the program after its prelude line.
+pub const WORKER: &str = r#"
+if [ "$2" = "-e" ]; then
+    printf '%s\n' "$3" | sed 1d
+    exit 0
+fi
+end=$(grep -o '<<arturo-kernel-end-[0-9a-f]*>>' "$2")
+done=$(grep -o '<<arturo-kernel-done-[0-9a-f]*>>' "$2")
+status=ok
+while IFS= read -r line; do
+    case "$line" in
+        "$end")
+            echo "$done $status"
+            [ "$status" = ok ] || echo boom >&2
+            status=ok ;;
+        *fail*) status=error ;;
+        *die*) exit 3 ;;
+        *hang*) while :; do sleep 0.05; done ;;
+        *) echo "$line" ;;
+    esac
+done
+"#;
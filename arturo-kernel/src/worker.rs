@@ -0,0 +1,283 @@
+//! Long-lived Arturo worker process.
+//!
+//! Instead of spawning `arturo -e` for every cell, the kernel keeps a single
+//! Arturo process alive running a small bootstrap script. Cells are written
+//! to the worker's stdin followed by an end-of-cell sentinel line; the worker
+//! evaluates the cell with `do` in its global scope and prints a done marker
+//! (carrying `ok` or `error`) on stdout once the cell has finished.
+//!
+//! `input` is rerouted through the input marker, so a cell that reads stdin
+//! is answered by the kernel (via the Jupyter stdin channel) instead of
+//! swallowing the next cell's source.
+
+use crate::{
+    config::Config,
+    interrupt::{self, Signal},
+    limits::{Limit, Watchdog},
+    markers::{self, MarkerScanner, Markers},
+    output::{self, CellIo, Event, Stream, StreamBuffer},
+};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::{Child, ChildStdin, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// How long to keep collecting stderr after the done marker arrives on stdout.
+/// The two pipes are read independently, so trailing error text can lag a
+/// little behind the marker.
+const STDERR_SETTLE: Duration = Duration::from_millis(50);
+
+/// Output captured from a worker that exited before finishing its cell.
+#[derive(Debug)]
+pub struct WorkerDied {
+    pub stdout: String,
+    pub stderr: String,
+    /// Set when the worker was stopped for exceeding a limit.
+    pub limit: Option<Limit>,
+}
+
+#[derive(Debug)]
+pub struct Worker {
+    child: Child,
+    /// Set once the child has been waited for. From then on its pid, and so
+    /// the id of its process group, may belong to someone else.
+    reaped: bool,
+    stdin: ChildStdin,
+    events: Receiver<Event>,
+    markers: Markers,
+}
+
+impl Worker {
+    /// Write the bootstrap script into `dir` and start Arturo on it.
+    pub fn spawn(dir: &Path, config: &Config) -> io::Result<Worker> {
+        let markers = Markers::new();
+
+        let script = dir.join("worker.art");
+        fs::write(&script, bootstrap_script(&markers))?;
+
+        let mut child = config
+            .command()
+            .arg("--no-color")
+            .arg(&script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let (tx, events) = mpsc::channel();
+        output::forward(stdout, Stream::Stdout, tx.clone());
+        output::forward(stderr, Stream::Stderr, tx);
+
+        Ok(Worker { child, reaped: false, stdin, events, markers })
+    }
+
+    /// Run one cell and wait for its done marker, passing output, `input`
+    /// prompts and displays to `io` as they arrive. `watchdog` enforces the
+    /// cell's limits and carries out interrupts.
+    ///
+    /// Returns `(stdout, stderr, is_error)` like `run_arturo`, or the partial
+    /// output if the worker exited before the cell completed.
+    pub fn run_cell(
+        &mut self,
+        code: &str,
+        io: &mut dyn CellIo,
+        watchdog: &mut Watchdog,
+    ) -> Result<(String, String, bool), WorkerDied> {
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let mut out = StreamBuffer::new(io);
+
+        let sent = writeln!(self.stdin, "{code}")
+            .and_then(|_| writeln!(self.stdin, "{}", self.markers.end))
+            .and_then(|_| self.stdin.flush());
+        if let Err(e) = sent {
+            stderr.push_str(&format!("Failed to send cell to Arturo worker: {e}\n"));
+            out.push(Stream::Stderr, &stderr);
+            return Err(WorkerDied { stdout, stderr, limit: None });
+        }
+
+        let mut scanner = MarkerScanner::new(&self.markers);
+        let mut open_pipes = 2;
+        while open_pipes > 0 {
+            watchdog.escalate(self.child.id());
+            if let Err(limit) = watchdog.expired() {
+                return Err(self.stop(stdout, stderr, limit));
+            }
+            let event = self.events.recv_timeout(watchdog.wait());
+            if let Ok(Event::Output(_, text)) = &event {
+                if let Err(limit) = watchdog.output(text) {
+                    return Err(self.stop(stdout, stderr, limit));
+                }
+            }
+            match event {
+                Ok(Event::Output(Stream::Stdout, text)) => {
+                    for piece in scanner.feed(&text) {
+                        let done = markers::handle(piece, &mut stdout, &mut self.stdin, &mut out);
+                        if let Some(status) = done {
+                            self.drain_stderr(&mut stderr, &mut out);
+                            return Ok((stdout, stderr, status.trim() != "ok"));
+                        }
+                    }
+                }
+                Ok(Event::Output(Stream::Stderr, text)) => {
+                    stderr.push_str(&text);
+                    out.push(Stream::Stderr, &text);
+                }
+                Ok(Event::Closed) => open_pipes -= 1,
+                Err(RecvTimeoutError::Timeout) => out.tick(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let rest = scanner.finish();
+        stdout.push_str(&rest);
+        out.push(Stream::Stdout, &rest);
+        let limit = self.reap().and_then(|status| watchdog.memory_exhausted(status, &stderr));
+        Err(WorkerDied { stdout, stderr, limit })
+    }
+
+    fn stop(&mut self, stdout: String, stderr: String, limit: Limit) -> WorkerDied {
+        interrupt::signal(self.child.id(), Signal::Kill);
+        self.reap();
+        WorkerDied { stdout, stderr, limit: Some(limit) }
+    }
+
+    fn reap(&mut self) -> Option<ExitStatus> {
+        let status = self.child.wait().ok();
+        self.reaped = true;
+        status
+    }
+
+    fn drain_stderr(&self, stderr: &mut String, out: &mut StreamBuffer) {
+        let deadline = Instant::now() + STDERR_SETTLE;
+        while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            match self.events.recv_timeout(wait) {
+                Ok(Event::Output(Stream::Stderr, text)) => {
+                    stderr.push_str(&text);
+                    out.push(Stream::Stderr, &text);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !self.reaped {
+            interrupt::signal(self.child.id(), Signal::Kill);
+            self.reap();
+        }
+    }
+}
+
+/// The Arturo program run by the worker: read lines until the end marker,
+/// evaluate them in the global scope, report completion, repeat.
+fn bootstrap_script(markers: &Markers) -> String {
+    format!(
+        r#"; arturo-kernel worker — generated, do not edit
+{prelude}
+while [true] [
+    arturoKernelSrc: new ""
+    arturoKernelLine: arturoKernelInput ""
+    while [arturoKernelLine <> "{end}"] [
+        arturoKernelSrc: arturoKernelSrc ++ arturoKernelLine ++ "\n"
+        arturoKernelLine: arturoKernelInput ""
+    ]
+    arturoKernelStatus: "error"
+    try.verbose [
+        do arturoKernelSrc
+        arturoKernelStatus: "ok"
+    ]
+    print ["{done}" arturoKernelStatus]
+]
+"#,
+        prelude = markers.prelude(),
+        end = markers.end,
+        done = markers.done,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Worker;
+    use crate::{
+        config::Config,
+        errors::ArturoError,
+        interrupt::Interrupt,
+        limits::Watchdog,
+        output::Stream,
+        sourcemap::{Location, SourceMap},
+        testing::{self, Recorder, TempDir},
+    };
+    use std::sync::Arc;
+
+    fn watchdog(config: &Config) -> Watchdog {
+        Watchdog::start(&config.limits(), &Arc::new(Interrupt::default()))
+    }
+
+    #[test]
+    fn cells_run_until_the_done_marker() {
+        let dir = TempDir::new();
+        let config = testing::stub(&dir, testing::WORKER);
+        let mut worker = Worker::spawn(dir.path(), &config).unwrap();
+        let mut io = Recorder::default();
+
+        let ran = worker.run_cell("a\nb", &mut io, &mut watchdog(&config)).unwrap();
+        assert_eq!(ran, ("a\nb\n".to_string(), String::new(), false));
+        // The same process takes the next cell.
+        let ran = worker.run_cell("c", &mut io, &mut watchdog(&config)).unwrap();
+        assert_eq!(ran, ("c\n".to_string(), String::new(), false));
+        assert_eq!(io.text(Stream::Stdout), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn stderr_after_an_error_marker_is_kept() {
+        let dir = TempDir::new();
+        let config = testing::stub(&dir, testing::WORKER);
+        let mut worker = Worker::spawn(dir.path(), &config).unwrap();
+        let mut io = Recorder::default();
+
+        let ran = worker.run_cell("x\nfail", &mut io, &mut watchdog(&config)).unwrap();
+        assert_eq!(ran, ("x\n".to_string(), "boom\n".to_string(), true));
+        assert_eq!(io.text(Stream::Stderr), "boom\n");
+    }
+
+    #[test]
+    fn a_worker_that_exits_mid_cell_has_died() {
+        let dir = TempDir::new();
+        let config = testing::stub(&dir, testing::WORKER);
+        let mut worker = Worker::spawn(dir.path(), &config).unwrap();
+
+        let died = worker.run_cell("x\ndie", &mut Recorder::default(), &mut watchdog(&config)).unwrap_err();
+        assert_eq!(died.stdout, "x\n");
+        assert!(died.limit.is_none());
+        assert!(worker.run_cell("y", &mut Recorder::default(), &mut watchdog(&config)).is_err());
+    }
+
+    /// Errors in the worker are mapped with `SourceMap::cell`, which assumes
+    /// Arturo numbers the lines of a `do` block from the start of the cell.
+    #[test]
+    #[ignore = "needs arturo on PATH"]
+    fn do_numbers_lines_from_the_start_of_the_cell() {
+        let dir = TempDir::new();
+        let config = Config::default();
+        let mut worker = Worker::spawn(dir.path(), &config).unwrap();
+
+        let code = "x: 1\n\ny: x + 1\nprint notDefinedAnywhere";
+        let (stdout, stderr, failed) = worker.run_cell(code, &mut Recorder::default(), &mut watchdog(&config)).unwrap();
+
+        assert!(failed);
+        let error = ArturoError::from_output(&stderr, &stdout, code, &SourceMap::cell(7, code));
+        assert_eq!(error.location, Some(Location { cell: 7, line: 4 }), "{stderr}");
+    }
+}